@@ -1,5 +1,5 @@
-drop table block_log cascade;
-create table block_log (
+-- deployments created from the former init.sql already have this table
+create table if not exists block_log (
    id serial primary key,
    block_number bigint not null unique,
    created_at timestamp not null default current_timestamp
@@ -0,0 +1,5 @@
+create table if not exists block_log (
+   id integer primary key autoincrement,
+   block_number integer not null unique,
+   created_at timestamp not null default current_timestamp
+);
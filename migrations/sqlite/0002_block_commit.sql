@@ -0,0 +1,18 @@
+create table if not exists processed_event (
+   tx_hash text not null,
+   log_index integer not null,
+   block_number integer not null,
+   event text not null,
+   created_at timestamp not null default current_timestamp,
+   primary key (tx_hash, log_index)
+);
+create index if not exists processed_event_block_number on processed_event (block_number);
+
+create table if not exists token_cache (
+   token_id integer primary key,
+   address text not null,
+   symbol text not null,
+   name text not null,
+   decimals integer not null,
+   updated_at timestamp not null default current_timestamp
+);
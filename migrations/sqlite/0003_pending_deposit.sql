@@ -0,0 +1,12 @@
+-- deposits of unlisted tokens, held instead of credited
+create table if not exists pending_deposit (
+   tx_hash text not null,
+   log_index integer not null,
+   block_number integer not null,
+   token_id integer not null,
+   token_address text not null,
+   user_id integer not null,
+   amount text not null,
+   created_at timestamp not null default current_timestamp,
+   primary key (tx_hash, log_index)
+);
@@ -0,0 +1,17 @@
+-- every step of a deposit through the listener, to answer "where is my deposit?"
+create table if not exists deposit (
+   tx_hash text not null,
+   log_index integer not null,
+   block_number integer not null,
+   user_id integer,
+   token_id integer not null,
+   asset text,
+   amount text not null,
+   delta text,
+   state text not null,
+   error text,
+   created_at timestamp not null default current_timestamp,
+   updated_at timestamp not null default current_timestamp,
+   primary key (tx_hash, log_index)
+);
+create index if not exists deposit_user_id on deposit (user_id);
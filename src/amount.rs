@@ -0,0 +1,166 @@
+use rust_decimal::Decimal;
+
+/// Largest scale a `Decimal` can hold.
+pub const MAX_SCALE: u32 = 28;
+
+/// Largest mantissa a `Decimal` can hold, 96 bits.
+const MAX_MANTISSA: u128 = (1 << 96) - 1;
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum AmountError {
+    #[error("amount {amount} with {decimals} decimals exceeds the 96 bits of a decimal")]
+    Overflow { amount: u128, decimals: u32 },
+    #[error("amount {amount} with {decimals} decimals has digits beyond the 28 of a decimal")]
+    TooPrecise { amount: u128, decimals: u32 },
+    #[error("amount {amount} is below the kept precision of {precision} decimals")]
+    BelowPrecision { amount: u128, precision: u32 },
+}
+
+/// A raw on-chain amount converted to a `Decimal`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Amount {
+    pub value: Decimal,
+    /// part of the raw amount below the kept precision, in the token's smallest unit
+    pub dust: u128,
+}
+
+/// `10^exp`, `None` beyond `u128`.
+fn pow10(exp: u32) -> Option<u128> {
+    10u128.checked_pow(exp)
+}
+
+/// `value` split into its part above the last `digits` digits and those digits.
+fn split_digits(value: u128, digits: u32) -> (u128, u128) {
+    match pow10(digits) {
+        Some(unit) => (value / unit, value % unit),
+        // the unit is larger than any value
+        None => (0, value),
+    }
+}
+
+/// Convert `raw`, in the smallest unit of a token with `decimals`, to a `Decimal`.
+///
+/// The digits beyond `precision` are truncated and returned as dust. Any other loss is
+/// refused: digits beyond `MAX_SCALE`, or an amount made only of dust.
+pub fn to_decimal(raw: u128, decimals: u32, precision: Option<u32>) -> Result<Amount, AmountError> {
+    let precision = decimals.min(precision.unwrap_or(decimals));
+    let (kept, dust) = split_digits(raw, decimals - precision);
+    if kept == 0 && dust > 0 {
+        return Err(AmountError::BelowPrecision {
+            amount: raw,
+            precision,
+        });
+    }
+    let scale = precision.min(MAX_SCALE);
+    let (kept, lost) = split_digits(kept, precision - scale);
+    if lost > 0 {
+        return Err(AmountError::TooPrecise {
+            amount: raw,
+            decimals,
+        });
+    }
+    if kept > MAX_MANTISSA {
+        return Err(AmountError::Overflow {
+            amount: raw,
+            decimals,
+        });
+    }
+    let value = Decimal::from_parts(
+        kept as u32,
+        (kept >> 32) as u32,
+        (kept >> 64) as u32,
+        false,
+        scale,
+    );
+    Ok(Amount { value, dust })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_to_decimal() {
+        let amount = to_decimal(1_500_000_000_000_000_000, 18, None).unwrap();
+        assert_eq!(Decimal::from_str("1.5").unwrap(), amount.value);
+        assert_eq!("1.500000000000000000", amount.value.to_string());
+        assert_eq!(0, amount.dust);
+
+        let amount = to_decimal(1_234_567, 6, Some(2)).unwrap();
+        assert_eq!("1.23", amount.value.to_string());
+        assert_eq!(4_567, amount.dust);
+
+        // more decimals than a `Decimal` can hold
+        let amount = to_decimal(10u128.pow(30), 30, None).unwrap();
+        assert_eq!(Decimal::new(1, 0), amount.value);
+        assert_eq!(0, amount.dust);
+        assert_eq!(
+            Err(AmountError::TooPrecise {
+                amount: 10u128.pow(30) + 7,
+                decimals: 30
+            }),
+            to_decimal(10u128.pow(30) + 7, 30, None)
+        );
+        assert!(to_decimal(u128::MAX, 77, None).is_err());
+
+        assert_eq!(
+            Err(AmountError::BelowPrecision {
+                amount: 4_567,
+                precision: 2
+            }),
+            to_decimal(4_567, 6, Some(2))
+        );
+        assert!(to_decimal(0, 6, Some(2)).unwrap().value.is_zero());
+
+        assert_eq!(
+            Err(AmountError::Overflow {
+                amount: u128::MAX,
+                decimals: 18
+            }),
+            to_decimal(u128::MAX, 18, None)
+        );
+        assert!(to_decimal(u128::MAX, 18, Some(6)).is_ok());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_kept_and_dust_add_up(
+            raw in any::<u128>(),
+            decimals in 0u32..80,
+            precision in proptest::option::of(0u32..40),
+        ) {
+            let precision = decimals.min(precision.unwrap_or(decimals));
+            match to_decimal(raw, decimals, Some(precision)) {
+                Ok(amount) => {
+                    let scale = precision.min(MAX_SCALE);
+                    prop_assert_eq!(scale, amount.value.scale());
+                    let kept = amount.value.mantissa() as u128;
+                    prop_assert!(kept > 0 || amount.dust == 0);
+                    // only the digits beyond `precision` may be dropped
+                    prop_assert!(pow10(decimals - precision).map_or(true, |unit| amount.dust < unit));
+                    match pow10(decimals - scale) {
+                        Some(unit) => prop_assert_eq!(raw, kept * unit + amount.dust),
+                        None => prop_assert_eq!(0, raw),
+                    }
+                }
+                Err(AmountError::Overflow { amount, .. }) => prop_assert_eq!(raw, amount),
+                Err(AmountError::TooPrecise { .. }) => prop_assert!(precision > MAX_SCALE),
+                Err(AmountError::BelowPrecision { .. }) => {
+                    prop_assert!(raw > 0);
+                    prop_assert!(pow10(decimals - precision).map_or(true, |unit| raw < unit));
+                }
+            }
+        }
+
+        #[test]
+        fn prop_matches_string_conversion(raw in any::<u64>(), decimals in 0u32..=MAX_SCALE) {
+            let mut expected = Decimal::from_str(&raw.to_string()).unwrap();
+            expected.set_scale(decimals).unwrap();
+            let amount = to_decimal(raw as u128, decimals, None).unwrap();
+            prop_assert_eq!(expected.to_string(), amount.value.to_string());
+            prop_assert_eq!(0, amount.dust);
+        }
+    }
+}
@@ -6,6 +6,9 @@ use ethers::core::types::{Block, H256};
 use ethers::prelude::{Middleware, Provider, ProviderError, PubsubClient, SubscriptionStream};
 use futures::Stream;
 
+use crate::health::HEALTH;
+use crate::metrics;
+
 #[derive(Debug, thiserror::Error)]
 pub enum ConfirmedBlockSubscribeError {
     #[error("provider got error when subscribe blocks: {0}")]
@@ -19,35 +22,90 @@ pub enum ConfirmedBlockStreamError {
 }
 
 type PollResult = Result<Option<Block<H256>>, ProviderError>;
+type HeadPollResult = Result<u64, ProviderError>;
+
+/// Decides which blocks are confirmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Finality {
+    /// blocks at least `n` blocks behind the newest block
+    Depth(u64),
+    /// blocks up to the node's `safe` block
+    Safe,
+    /// blocks up to the node's `finalized` block
+    Finalized,
+}
+
+impl Finality {
+    fn tag(self) -> Option<&'static str> {
+        match self {
+            Finality::Depth(_) => None,
+            Finality::Safe => Some("safe"),
+            Finality::Finalized => Some("finalized"),
+        }
+    }
+}
+
+async fn tagged_block_number<P: PubsubClient>(
+    provider: &Provider<P>,
+    tag: &'static str,
+) -> HeadPollResult {
+    metrics::rpc_call("eth_getBlockByNumber");
+    let block: Option<Block<H256>> = provider
+        .request("eth_getBlockByNumber", (tag, false))
+        .await?;
+    Ok(block
+        .and_then(|block| block.number)
+        .map_or(0, |number| number.as_u64()))
+}
 
 pub struct ConfirmedBlockStream<'a, P: PubsubClient> {
     provider: &'a Provider<P>,
     rx: Pin<Box<SubscriptionStream<'a, P, Block<H256>>>>,
     last_confirmed_block: u64,
     newest_block: u64,
-    n_confirmations: u64,
+    /// the newest block which is confirmed under `finality`
+    confirmed_head: u64,
+    finality: Finality,
     last_poll: Option<Pin<Box<(dyn Future<Output = PollResult> + 'a)>>>,
+    head_poll: Option<Pin<Box<(dyn Future<Output = HeadPollResult> + 'a)>>>,
 }
 
 impl<'a, P: PubsubClient> ConfirmedBlockStream<'a, P> {
     pub async fn new(
         provider: &'a Provider<P>,
         from: u64,
-        n_confirmations: u64,
+        finality: Finality,
     ) -> Result<ConfirmedBlockStream<'a, P>, ConfirmedBlockSubscribeError> {
+        metrics::rpc_call("eth_subscribe");
         let rx = provider.subscribe_blocks().await?;
         debug!("subscribed on eth blocks");
+        HEALTH.set_subscribed(true);
+        HEALTH.block_seen();
+        metrics::rpc_call("eth_blockNumber");
         let newest_block = provider.get_block_number().await?.as_u64();
         debug!("current eth block is block#{}", newest_block);
+        metrics::CHAIN_HEAD.set(newest_block as i64);
+        let confirmed_head = match finality {
+            Finality::Depth(n) => newest_block.saturating_sub(n),
+            _ => tagged_block_number(provider, finality.tag().unwrap()).await?,
+        };
+        debug!("confirmed head is block#{}", confirmed_head);
         Ok(Self {
             provider,
             rx: Box::pin(rx),
             last_confirmed_block: from,
             newest_block,
-            n_confirmations,
+            confirmed_head,
+            finality,
             last_poll: None,
+            head_poll: None,
         })
     }
+
+    /// Change the finality, effective from the next block observed.
+    pub fn set_finality(&mut self, finality: Finality) {
+        self.finality = finality;
+    }
 }
 
 impl<'a, P: PubsubClient> Stream for ConfirmedBlockStream<'a, P> {
@@ -63,6 +121,7 @@ impl<'a, P: PubsubClient> Stream for ConfirmedBlockStream<'a, P> {
                 let ret = match poll_result {
                     Ok(Some(block)) => {
                         this.last_confirmed_block = block.number.unwrap().as_u64();
+                        HEALTH.block_seen();
                         debug!(
                             "confirm block#{} (latest block at #{})",
                             this.last_confirmed_block, this.newest_block,
@@ -81,13 +140,30 @@ impl<'a, P: PubsubClient> Stream for ConfirmedBlockStream<'a, P> {
             return Poll::Pending;
         }
 
+        // poll the confirmed head if the finality is decided by the node
+        if let Some(mut fut) = this.head_poll.take() {
+            debug!("polling pending confirmed head future");
+            match fut.as_mut().poll(cx) {
+                Poll::Ready(Ok(confirmed_head)) => {
+                    debug!("confirmed head updated to block#{}", confirmed_head);
+                    this.confirmed_head = this.confirmed_head.max(confirmed_head);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Pending => {
+                    this.head_poll.replace(fut);
+                    return Poll::Pending;
+                }
+            }
+        }
+
         // assign future if there is remaining block
-        if this.last_confirmed_block < this.newest_block.saturating_sub(this.n_confirmations) {
+        if this.last_confirmed_block < this.confirmed_head {
             debug!(
                 "assign new future for block#{} (latest block at #{})",
                 this.last_confirmed_block + 1,
                 this.newest_block,
             );
+            metrics::rpc_call("eth_getBlockByNumber");
             let fut = Box::pin(this.provider.get_block(this.last_confirmed_block + 1));
             this.last_poll.replace(fut);
             // immediately poll after create
@@ -99,12 +175,25 @@ impl<'a, P: PubsubClient> Stream for ConfirmedBlockStream<'a, P> {
         let block = match futures_util::ready!(this.rx.as_mut().poll_next(cx)) {
             Some(block) => block,
             // the stream is terminated
-            None => return Poll::Ready(None),
+            None => {
+                HEALTH.set_subscribed(false);
+                return Poll::Ready(None);
+            }
         };
 
         // we got new block here, update
         this.newest_block = block.number.unwrap().as_u64();
         debug!("newest_block updated to block#{}", this.newest_block);
+        metrics::CHAIN_HEAD.set(this.newest_block as i64);
+        HEALTH.block_seen();
+        match this.finality {
+            Finality::Depth(n) => this.confirmed_head = this.newest_block.saturating_sub(n),
+            finality => {
+                let tag = finality.tag().unwrap();
+                this.head_poll
+                    .replace(Box::pin(tagged_block_number(this.provider, tag)));
+            }
+        }
         Pin::new(this).poll_next(cx)
     }
 }
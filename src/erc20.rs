@@ -4,7 +4,9 @@ use serde::Deserialize;
 use std::convert::TryFrom;
 use std::ops::Deref;
 use std::str::FromStr;
+use std::sync::Arc;
 
+use crate::metrics;
 use crate::restapi::Asset;
 
 const MIN_ABI: &str = r#"[
@@ -38,7 +40,105 @@ const MIN_ABI: &str = r#"[
   }
 ]"#;
 
+/// `symbol` and `name` of tokens predating the standard, e.g. MKR.
+const BYTES32_ABI: &str = r#"[
+  {
+    "name":"symbol",
+    "inputs":[],
+    "outputs":[{"name":"","type":"bytes32"}],
+    "type":"function",
+    "constant":true
+  },
+  {
+    "name":"name",
+    "inputs":[],
+    "outputs":[{"name":"","type":"bytes32"}],
+    "type":"function",
+    "constant":true
+  }
+]"#;
+
 static ABI: Lazy<Abi> = Lazy::new(|| serde_json::from_str(MIN_ABI).unwrap());
+static FALLBACK_ABI: Lazy<Abi> = Lazy::new(|| serde_json::from_str(BYTES32_ABI).unwrap());
+
+#[derive(Debug, thiserror::Error)]
+pub enum Erc20QueryError {
+    /// the node couldn't be asked, retrying later may succeed
+    #[error("{method}() of token {address:#x} couldn't be sent: {reason}")]
+    Provider {
+        address: Address,
+        method: &'static str,
+        reason: String,
+    },
+    /// the token reverted or returned something undecodable
+    #[error("{method}() of token {address:#x} failed: {reason}")]
+    Call {
+        address: Address,
+        method: &'static str,
+        reason: String,
+    },
+    #[error("{method}() of token {address:#x} returned an empty value")]
+    Empty {
+        address: Address,
+        method: &'static str,
+    },
+}
+
+impl Erc20QueryError {
+    pub fn address(&self) -> Address {
+        match self {
+            Erc20QueryError::Provider { address, .. }
+            | Erc20QueryError::Call { address, .. }
+            | Erc20QueryError::Empty { address, .. } => *address,
+        }
+    }
+
+    /// Whether the query failed for reasons unrelated to the token.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Erc20QueryError::Provider { .. })
+    }
+}
+
+/// Whether a failed call was answered by the node with a revert.
+fn is_revert(reason: &str) -> bool {
+    let reason = reason.to_ascii_lowercase();
+    reason.contains("revert") || reason.contains("invalid opcode")
+}
+
+fn call_error<M: Middleware>(
+    address: Address,
+    method: &'static str,
+    error: ContractError<M>,
+) -> Erc20QueryError {
+    let reason = error.to_string();
+    match error {
+        ContractError::MiddlewareError(_) | ContractError::ProviderError(_)
+            if !is_revert(&reason) =>
+        {
+            Erc20QueryError::Provider {
+                address,
+                method,
+                reason,
+            }
+        }
+        _ => Erc20QueryError::Call {
+            address,
+            method,
+            reason,
+        },
+    }
+}
+
+/// Decode a NUL padded `bytes32` string.
+fn bytes32_to_string(raw: &[u8]) -> Option<String> {
+    let len = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+    let text = std::str::from_utf8(&raw[..len]).ok()?.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct ERC20 {
@@ -52,53 +152,82 @@ pub struct ERC20 {
 pub struct LocalToken {
     pub symbol: String,
     pub address: String,
+    pub decimals: Option<u8>,
 }
 
 impl ERC20 {
-    pub async fn query<M: Middleware>(client: M, address: Address) -> Self {
-        let contract = Contract::new(address, ABI.deref().clone(), client);
-
-        let symbol = contract
-            .method::<_, String>("symbol", ())
-            .unwrap()
-            .call()
-            .await
-            .unwrap_or_else(|_| "".to_string());
+    pub async fn query<M: Middleware>(
+        client: M,
+        address: Address,
+    ) -> Result<Self, Erc20QueryError> {
+        let client = Arc::new(client);
+        let contract = Contract::new(address, ABI.deref().clone(), client.clone());
+        let fallback = Contract::new(address, FALLBACK_ABI.deref().clone(), client);
 
-        let name = contract
-            .method::<_, String>("name", ())
-            .unwrap()
-            .call()
-            .await
-            .unwrap_or_else(|_| "".to_string());
+        let symbol = query_text(&contract, &fallback, "symbol").await?;
+        let name = query_text(&contract, &fallback, "name").await?;
 
+        metrics::rpc_call("eth_call");
         let decimals = contract
             .method::<_, u8>("decimals", ())
             .unwrap()
             .call()
             .await
-            .unwrap();
+            .map_err(|e| call_error(address, "decimals", e))?;
 
-        Self {
+        Ok(Self {
             address,
             symbol,
             name,
             decimals,
+        })
+    }
+}
+
+/// Call a `string` getter, falling back to its `bytes32` variant.
+async fn query_text<M: Middleware>(
+    contract: &Contract<M>,
+    fallback: &Contract<M>,
+    method: &'static str,
+) -> Result<String, Erc20QueryError> {
+    let address = contract.address();
+    metrics::rpc_call("eth_call");
+    match contract
+        .method::<_, String>(method, ())
+        .unwrap()
+        .call()
+        .await
+    {
+        Ok(text) if !text.trim().is_empty() => return Ok(text.trim().to_string()),
+        Ok(_) => debug!("{}() of token {:#x} is empty", method, address),
+        Err(e) => {
+            let error = call_error(address, method, e);
+            if error.is_transient() {
+                return Err(error);
+            }
+            debug!("{}, trying bytes32", error);
         }
     }
+    metrics::rpc_call("eth_call");
+    let raw = fallback
+        .method::<_, H256>(method, ())
+        .unwrap()
+        .call()
+        .await
+        .map_err(|e| call_error(address, method, e))?;
+    bytes32_to_string(raw.as_bytes()).ok_or(Erc20QueryError::Empty { address, method })
 }
 
-impl From<(ERC20, u16)> for Asset {
-    fn from((erc20, token_id): (ERC20, u16)) -> Self {
+impl From<(ERC20, u16, i16)> for Asset {
+    fn from((erc20, token_id, chain_id): (ERC20, u16, i16)) -> Self {
         Self {
             id: erc20.symbol.clone(),
             symbol: erc20.symbol,
             name: erc20.name,
-            // reference: dingir-exchange/migrations/20210223072038_markets_preset.sql
-            chain_id: 1,
+            chain_id,
             token_address: format!("{:#x}", erc20.address),
             rollup_token_id: token_id as i32,
-            // TODO: review this
+            // defaults, operators can change them with `tokens.overrides`
             prec_save: 6,
             prec_show: 6,
             logo_uri: "".to_string(),
@@ -115,7 +244,7 @@ impl TryFrom<LocalToken> for ERC20 {
             address,
             symbol: token.symbol.clone(),
             name: token.symbol,
-            decimals: 6,
+            decimals: token.decimals.unwrap_or(6),
         })
     }
 }
@@ -135,7 +264,9 @@ mod tests {
     async fn test() {
         let provider = Provider::try_from(INFURA).unwrap();
 
-        let token = ERC20::query(provider, TEST_TOKEN.parse().unwrap()).await;
+        let token = ERC20::query(provider, TEST_TOKEN.parse().unwrap())
+            .await
+            .unwrap();
         assert_eq!("USDT", token.symbol);
         assert_eq!("Tether USD (Fluidex Test)", token.name);
         assert_eq!(6, token.decimals);
@@ -147,9 +278,33 @@ mod tests {
         let ws = Ws::new(ws);
         let provider = Provider::new(ws);
 
-        let token = ERC20::query(provider, TEST_TOKEN.parse().unwrap()).await;
+        let token = ERC20::query(provider, TEST_TOKEN.parse().unwrap())
+            .await
+            .unwrap();
         assert_eq!("USDT", token.symbol);
         assert_eq!("Tether USD (Fluidex Test)", token.name);
         assert_eq!(6, token.decimals);
     }
+
+    #[test]
+    fn test_bytes32_to_string() {
+        let mut mkr = [0u8; 32];
+        mkr[..3].copy_from_slice(b"MKR");
+        assert_eq!(Some("MKR".to_string()), bytes32_to_string(&mkr));
+        assert_eq!(None, bytes32_to_string(&[0u8; 32]));
+        assert_eq!(None, bytes32_to_string(&[0xff; 32]));
+    }
+
+    #[test]
+    fn test_is_revert() {
+        assert!(is_revert(
+            "(code: 3, message: execution reverted, data: None)"
+        ));
+        assert!(is_revert(
+            "VM Exception while processing transaction: revert"
+        ));
+        assert!(!is_revert(
+            "error sending request for url (https://rpc/): timed out"
+        ));
+    }
 }
@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::BoxFuture;
+use once_cell::sync::{Lazy, OnceCell};
+
+pub static HEALTH: Lazy<Health> = Lazy::new(Health::new);
+
+/// Time a probe may take before its dependency counts as down.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A cheap request checking that a dependency answers.
+pub type Probe = Box<dyn Fn() -> BoxFuture<'static, bool> + Send + Sync>;
+
+/// Liveness and readiness state shared between the listener and the http server.
+pub struct Health {
+    subscribed: AtomicBool,
+    db_connected: AtomicBool,
+    grpc_connected: AtomicBool,
+    /// unix timestamp of the last block produced or observed by `ConfirmedBlockStream`
+    last_block_at: AtomicU64,
+    /// run on every readiness check, the flags above are only updated by traffic
+    db_probe: OnceCell<Probe>,
+    grpc_probe: OnceCell<Probe>,
+}
+
+/// Run `probe` if set, `None` otherwise.
+async fn run_probe(probe: &OnceCell<Probe>) -> Option<bool> {
+    let probe = probe.get()?;
+    Some(
+        tokio::time::timeout(PROBE_TIMEOUT, probe())
+            .await
+            .unwrap_or(false),
+    )
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Readiness {
+    pub subscribed: bool,
+    pub db_connected: bool,
+    pub grpc_connected: bool,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+impl Health {
+    fn new() -> Self {
+        Self {
+            subscribed: AtomicBool::new(false),
+            db_connected: AtomicBool::new(false),
+            grpc_connected: AtomicBool::new(false),
+            // count the startup as activity, so a slow start is not reported dead
+            last_block_at: AtomicU64::new(now()),
+            db_probe: OnceCell::new(),
+            grpc_probe: OnceCell::new(),
+        }
+    }
+
+    /// Check the database with `probe` on readiness checks, only the first probe set is kept.
+    pub fn set_db_probe(&self, probe: Probe) {
+        let _ = self.db_probe.set(probe);
+    }
+
+    /// Check the exchange with `probe` on readiness checks, only the first probe set is kept.
+    pub fn set_grpc_probe(&self, probe: Probe) {
+        let _ = self.grpc_probe.set(probe);
+    }
+
+    pub fn set_subscribed(&self, subscribed: bool) {
+        self.subscribed.store(subscribed, Ordering::Relaxed);
+    }
+
+    pub fn set_db_connected(&self, connected: bool) {
+        self.db_connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn set_grpc_connected(&self, connected: bool) {
+        self.grpc_connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn block_seen(&self) {
+        self.last_block_at.store(now(), Ordering::Relaxed);
+    }
+
+    /// Whether a block was produced or observed within `window`.
+    pub fn is_alive(&self, window: Duration) -> bool {
+        now().saturating_sub(self.last_block_at.load(Ordering::Relaxed)) <= window.as_secs()
+    }
+
+    /// Probe the dependencies, those without a probe are reported as last seen.
+    pub async fn readiness(&self) -> Readiness {
+        let (db, grpc) = futures::join!(run_probe(&self.db_probe), run_probe(&self.grpc_probe));
+        if let Some(connected) = db {
+            self.set_db_connected(connected);
+        }
+        if let Some(connected) = grpc {
+            self.set_grpc_connected(connected);
+        }
+        Readiness {
+            subscribed: self.subscribed.load(Ordering::Relaxed),
+            db_connected: self.db_connected.load(Ordering::Relaxed),
+            grpc_connected: self.grpc_connected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.subscribed && self.db_connected && self.grpc_connected
+    }
+}
@@ -4,7 +4,13 @@ use std::sync::Arc;
 
 use ethers::prelude::*;
 
-use crate::erc20::{LocalToken, ERC20};
+use crate::config::Tokens;
+#[cfg(not(feature = "offline"))]
+use crate::config::UnresolvedTokenPolicy;
+use crate::erc20::{Erc20QueryError, LocalToken, ERC20};
+#[cfg(not(feature = "offline"))]
+use crate::metrics;
+use crate::persist::TokenRecord;
 use crate::restapi::Asset;
 use crate::Fluidex;
 
@@ -12,10 +18,15 @@ use crate::Fluidex;
 pub struct ContractInfos<M: Middleware> {
     provider: Arc<M>,
     contract: Fluidex<M>,
+    chain_id: i16,
+    /// the `[tokens]` settings: lists, overrides and the policy for unqueryable tokens
+    tokens: Tokens,
     token_ids: HashMap<u16, Address>,
     token_addresses: HashMap<Address, u16>,
     user_ids: HashMap<[u8; 32], u16>,
     erc20s: HashMap<Address, ERC20>,
+    /// tokens learned since the last `take_token_updates`
+    token_updates: Vec<TokenRecord>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -24,6 +35,10 @@ pub enum ContractInfoError {
     ContractError(String),
     #[error("non existing entry")]
     NonExistEntry,
+    #[error(transparent)]
+    Erc20(#[from] Erc20QueryError),
+    #[error("token {0:#x} is quarantined: {1}")]
+    Quarantined(Address, Erc20QueryError),
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -34,18 +49,34 @@ pub struct Account {
 
 type Result<T, E = ContractInfoError> = std::result::Result<T, E>;
 
+/// Token id of the chain's native coin, which has no contract.
+pub const NATIVE_TOKEN_ID: u16 = 0;
+
 impl<M: Middleware> ContractInfos<M> {
-    pub async fn new(provider: Arc<M>, address: Address) -> Self {
+    /// `native` is registered as token `NATIVE_TOKEN_ID`.
+    pub async fn new(
+        provider: Arc<M>,
+        address: Address,
+        chain_id: i16,
+        native: ERC20,
+        tokens: Tokens,
+    ) -> Self {
         let contract = Fluidex::new(address, provider.clone());
 
-        let info = ContractInfos {
+        let mut info = ContractInfos {
             provider,
             contract,
+            chain_id,
+            tokens,
             token_ids: HashMap::new(),
             token_addresses: HashMap::new(),
             user_ids: HashMap::new(),
             erc20s: HashMap::new(),
+            token_updates: Vec::new(),
         };
+        info.token_ids.insert(NATIVE_TOKEN_ID, native.address);
+        info.token_addresses.insert(native.address, NATIVE_TOKEN_ID);
+        info.erc20s.insert(native.address, native);
 
         if cfg!(feature = "offline") {
             info!("loading tokens from local file");
@@ -79,47 +110,185 @@ impl<M: Middleware> ContractInfos<M> {
         Ok(self)
     }
 
-    pub async fn add_token(&mut self, address: Address, token_id: u16) -> Asset {
+    pub async fn add_token(&mut self, address: Address, token_id: u16) -> Result<Asset> {
         self.token_ids.insert(token_id, address);
         self.token_addresses.insert(address, token_id);
-        let erc20 = self.fetch_erc20(address).await;
-        (erc20, token_id).into()
+        let erc20 = self.fetch_erc20(address).await?;
+        self.record_token(token_id, address);
+        Ok(self.to_asset(erc20, token_id))
+    }
+
+    /// Queue the cached metadata of a token to be persisted with the block. It is persisted
+    /// as queried, the overrides apply when the token is read.
+    fn record_token(&mut self, token_id: u16, address: Address) {
+        if let Some(queried) = self.erc20s.get(&address) {
+            let record = TokenRecord {
+                token_id,
+                address: format!("{:#x}", address),
+                symbol: queried.symbol.clone(),
+                name: queried.name.clone(),
+                decimals: queried.decimals,
+            };
+            self.token_updates
+                .retain(|token| token.token_id != token_id);
+            self.token_updates.push(record);
+        }
+    }
+
+    /// Replace the `[tokens]` settings, e.g. once they are reloaded.
+    pub fn set_tokens(&mut self, tokens: Tokens) {
+        self.tokens = tokens;
+    }
+
+    /// Whether the token at `address` passes `tokens.allowlist` and `tokens.denylist`.
+    /// The native coin is always listed.
+    pub fn is_listed(&self, address: Address) -> bool {
+        self.token_addresses.get(&address) == Some(&NATIVE_TOKEN_ID)
+            || self.tokens.is_listed(address)
+    }
+
+    /// A token's metadata with the operator's overrides applied. They are applied on every
+    /// read rather than cached, so reloaded overrides apply to known tokens as well.
+    fn with_overrides(&self, mut erc20: ERC20) -> ERC20 {
+        let token_id = self.token_addresses.get(&erc20.address).copied();
+        if let Some(token) = self.tokens.find(erc20.address, token_id) {
+            token.apply_to_erc20(&mut erc20);
+        }
+        erc20
+    }
+
+    /// The exchange asset of a token, with the operator's overrides applied.
+    fn to_asset(&self, erc20: ERC20, token_id: u16) -> Asset {
+        let address = erc20.address;
+        let mut asset: Asset = (erc20, token_id, self.chain_id).into();
+        if let Some(token) = self.tokens.find(address, Some(token_id)) {
+            token.apply_to_asset(&mut asset);
+        }
+        asset
+    }
+
+    /// Fill the caches with tokens persisted by earlier runs.
+    pub fn seed_tokens(&mut self, tokens: Vec<TokenRecord>) {
+        for token in tokens {
+            let address = match token.address.parse() {
+                Ok(address) => address,
+                Err(_) => {
+                    warn!("ignoring cached token with address {}", token.address);
+                    continue;
+                }
+            };
+            self.token_ids.insert(token.token_id, address);
+            self.token_addresses.insert(address, token.token_id);
+            self.erc20s.insert(
+                address,
+                ERC20 {
+                    address,
+                    symbol: token.symbol,
+                    name: token.name,
+                    decimals: token.decimals,
+                },
+            );
+        }
+    }
+
+    /// Tokens learned since the last call, to be persisted with the block.
+    pub fn take_token_updates(&mut self) -> Vec<TokenRecord> {
+        std::mem::take(&mut self.token_updates)
     }
 
     #[cfg(not(feature = "offline"))]
-    pub async fn fetch_erc20(&mut self, address: Address) -> ERC20 {
-        if let Some(erc20) = self.erc20s.get(&address) {
-            return erc20.clone();
+    pub async fn fetch_erc20(&mut self, address: Address) -> Result<ERC20> {
+        let cached = self.erc20s.get(&address);
+        metrics::cache_lookup("erc20", cached.is_some());
+        if let Some(erc20) = cached {
+            return Ok(self.with_overrides(erc20.clone()));
         }
-        let erc20 = ERC20::query(&self.provider, address).await;
+        let token_id = self.token_addresses.get(&address).copied();
+        let erc20 = match ERC20::query(&self.provider, address).await {
+            Ok(erc20) => erc20,
+            Err(e) => resolve_unqueryable(&self.tokens, address, token_id, e)?,
+        };
         self.erc20s.insert(address, erc20.clone());
-        erc20
+        if let Some(token_id) = token_id {
+            self.record_token(token_id, address);
+        }
+        Ok(self.with_overrides(erc20))
     }
 
     #[cfg(feature = "offline")]
-    pub async fn fetch_erc20(&mut self, address: Address) -> ERC20 {
-        return self.erc20s.get(&address).unwrap().clone();
+    pub async fn fetch_erc20(&mut self, address: Address) -> Result<ERC20> {
+        let erc20 = self
+            .erc20s
+            .get(&address)
+            .cloned()
+            .ok_or(ContractInfoError::NonExistEntry)?;
+        Ok(self.with_overrides(erc20))
     }
 
     pub async fn fetch_assets(&mut self, token_id: u16) -> Result<Asset> {
         let address = self.fetch_token_address(token_id).await?;
-        return Ok((self.fetch_erc20(address).await, token_id).into());
+        let erc20 = self.fetch_erc20(address).await?;
+        Ok(self.to_asset(erc20, token_id))
+    }
+
+    /// Ids of all tokens registered on chain, which are assigned sequentially from 1.
+    #[cfg(not(feature = "offline"))]
+    pub async fn fetch_token_ids(&mut self) -> Result<Vec<u16>> {
+        let mut token_ids = Vec::new();
+        for token_id in 1..=u16::MAX {
+            if self.token_ids.contains_key(&token_id) {
+                token_ids.push(token_id);
+                continue;
+            }
+            metrics::rpc_call("eth_call");
+            let address = self
+                .contract
+                .token_id_to_addr(token_id)
+                .call()
+                .await
+                .map_err(|e| ContractInfoError::ContractError(format!("{:?}", e)))?;
+            if address.is_zero() {
+                break;
+            }
+            token_ids.push(token_id);
+        }
+        Ok(token_ids)
+    }
+
+    #[cfg(feature = "offline")]
+    pub async fn fetch_token_ids(&mut self) -> Result<Vec<u16>> {
+        let mut token_ids: Vec<u16> = self
+            .token_ids
+            .keys()
+            .copied()
+            .filter(|token_id| *token_id != NATIVE_TOKEN_ID)
+            .collect();
+        token_ids.sort_unstable();
+        Ok(token_ids)
     }
 
     #[cfg(not(feature = "offline"))]
     pub async fn fetch_token_address(&mut self, token_id: u16) -> Result<Address> {
-        if let Some(address) = self.token_ids.get(&token_id) {
+        let cached = self.token_ids.get(&token_id);
+        metrics::cache_lookup("token_address", cached.is_some());
+        if let Some(address) = cached {
             return Ok(*address);
         }
+        metrics::rpc_call("eth_call");
         let address = self
             .contract
             .token_id_to_addr(token_id)
             .call()
             .await
             .map_err(|e| ContractInfoError::ContractError(format!("{:?}", e)))?;
+        // the contract maps unregistered ids to the zero address, don't cache it as a token
+        if token_id != NATIVE_TOKEN_ID && address.is_zero() {
+            error!("trying fetch non exist token #{}", token_id);
+            return Err(ContractInfoError::NonExistEntry);
+        }
+        // the metadata is queried on first use, the token may not even be listed
         self.token_ids.insert(token_id, address);
         self.token_addresses.insert(address, token_id);
-        self.add_token(address, token_id).await;
         Ok(address)
     }
 
@@ -135,9 +304,12 @@ impl<M: Middleware> ContractInfos<M> {
 
     #[cfg(not(feature = "offline"))]
     pub async fn fetch_token_id(&mut self, address: Address) -> Result<u16> {
-        if let Some(token_id) = self.token_addresses.get(&address) {
+        let cached = self.token_addresses.get(&address);
+        metrics::cache_lookup("token_id", cached.is_some());
+        if let Some(token_id) = cached {
             return Ok(*token_id);
         }
+        metrics::rpc_call("eth_call");
         let token_id = self
             .contract
             .token_addr_to_id(address)
@@ -146,7 +318,7 @@ impl<M: Middleware> ContractInfos<M> {
             .map_err(|e| ContractInfoError::ContractError(format!("{:?}", e)))?;
         self.token_ids.insert(token_id, address);
         self.token_addresses.insert(address, token_id);
-        self.add_token(address, token_id).await;
+        self.add_token(address, token_id).await?;
         Ok(token_id)
     }
 
@@ -162,9 +334,12 @@ impl<M: Middleware> ContractInfos<M> {
 
     #[cfg(not(feature = "offline"))]
     pub async fn fetch_user_id(&mut self, pubkey: &[u8; 32]) -> Result<u16> {
-        if let Some(user_id) = self.user_ids.get(pubkey) {
+        let cached = self.user_ids.get(pubkey);
+        metrics::cache_lookup("user_id", cached.is_some());
+        if let Some(user_id) = cached {
             return Ok(*user_id);
         }
+        metrics::rpc_call("eth_call");
         let user_id = self
             .contract
             .user_bjj_pubkey_to_user_id(*pubkey)
@@ -193,6 +368,32 @@ impl<M: Middleware> ContractInfos<M> {
     }
 }
 
+/// Apply the configured policy to a token whose metadata can't be queried.
+/// Failures to reach the node are returned as they are.
+#[cfg(not(feature = "offline"))]
+fn resolve_unqueryable(
+    tokens: &Tokens,
+    address: Address,
+    token_id: Option<u16>,
+    error: Erc20QueryError,
+) -> Result<ERC20> {
+    // the token isn't at fault, the block is retried
+    if error.is_transient() {
+        return Err(error.into());
+    }
+    match tokens.unresolved() {
+        UnresolvedTokenPolicy::Reject => Err(error.into()),
+        UnresolvedTokenPolicy::Override => match tokens.override_for(address, token_id) {
+            Some(erc20) => {
+                warn!("using the configured override for token: {}", error);
+                Ok(erc20)
+            }
+            None => Err(error.into()),
+        },
+        UnresolvedTokenPolicy::Quarantine => Err(ContractInfoError::Quarantined(address, error)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use ethers::prelude::*;
@@ -202,6 +403,7 @@ mod tests {
     use super::*;
 
     const INFURA: &'static str = "https://goerli.infura.io/v3/71e500f0f56944fa80641312fdd9a6a4";
+    const GOERLI: i16 = 5;
     const CONTRACT_ADDRESS: &'static str = "0x1e8b07682E5ED8e7a666605a78B74cBdc7dC9455";
 
     const ERC20_1: &'static str = "0x46490225a85ddfd9d79256f8c5393c0428121488";
@@ -211,8 +413,31 @@ mod tests {
     #[tokio::test]
     async fn test_read() {
         let provider = Arc::new(Provider::try_from(INFURA).unwrap());
-        let mut contract_info =
-            ContractInfos::new(provider, CONTRACT_ADDRESS.parse().unwrap()).await;
+        let native = ERC20 {
+            address: Address::zero(),
+            symbol: "ETH".to_string(),
+            name: "Ether".to_string(),
+            decimals: 18,
+        };
+        let mut contract_info = ContractInfos::new(
+            provider,
+            CONTRACT_ADDRESS.parse().unwrap(),
+            GOERLI,
+            native,
+            Tokens::default(),
+        )
+        .await;
+
+        // native coin
+        let address = contract_info
+            .fetch_token_address(NATIVE_TOKEN_ID)
+            .await
+            .unwrap();
+        assert!(address.is_zero());
+        assert_eq!(
+            18,
+            contract_info.fetch_erc20(address).await.unwrap().decimals
+        );
 
         // read erc20
         let address = contract_info.fetch_token_address(1).await.unwrap();
@@ -223,7 +448,7 @@ mod tests {
         assert_eq!("USDT", asset.id);
         assert_eq!("USDT", asset.symbol);
         assert_eq!("Tether USD (Fluidex Test)", asset.name);
-        assert_eq!(1, asset.chain_id);
+        assert_eq!(GOERLI, asset.chain_id);
         assert_eq!(ERC20_1, asset.token_address.to_ascii_lowercase());
         assert_eq!(1, asset.rollup_token_id);
         assert_eq!(6, asset.prec_save);
@@ -10,12 +10,23 @@ pub use crate::block_stream::ConfirmedBlockStream;
 pub use crate::config::CONFIG;
 pub use crate::fluidex::Fluidex;
 
+pub mod amount;
 pub mod block_stream;
 pub mod config;
 pub mod erc20;
+pub mod health;
 pub mod infos;
+pub mod metrics;
+pub mod pending;
 pub mod persist;
+pub mod processor;
+pub mod provider;
+pub mod rate_limit;
+pub mod reconcile;
 pub mod restapi;
+pub mod secret;
+pub mod server;
+pub mod shutdown;
 
 pub mod events {
     #![allow(clippy::all)]
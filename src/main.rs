@@ -1,177 +1,551 @@
 #[macro_use]
 extern crate log;
 
-use std::convert::TryFrom;
-use std::str::FromStr;
+use std::env;
 use std::sync::Arc;
 use std::time::Duration;
-#[cfg(feature = "new_token")]
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
-use eth_listener::events::*;
+use eth_listener::events::Events;
 use eth_listener::exchange::matchengine_client::MatchengineClient;
-use eth_listener::exchange::{BalanceUpdateRequest, EthLogMetadata, UserInfo};
+use eth_listener::health::HEALTH;
 use eth_listener::infos::ContractInfos;
-use eth_listener::persist::Persistor;
-#[cfg(feature = "new_token")]
-use eth_listener::restapi::{NewAssetReq, RestClient};
+use eth_listener::pending::DEPOSITS;
+use eth_listener::persist::{BlockRecord, Persistor, ProcessedEvent};
+use eth_listener::processor::{self, Processor};
+use eth_listener::provider::{self, FailoverClient, HttpProvider};
+use eth_listener::rate_limit::{self, RateLimited};
+use eth_listener::reconcile::reconcile_assets;
+use eth_listener::restapi::RestClient;
+use eth_listener::shutdown::{Shutdown, Signal};
 use eth_listener::ConfirmedBlockStream;
 use eth_listener::CONFIG;
+use eth_listener::{config, metrics, server};
 use ethers::prelude::*;
-use rust_decimal::Decimal;
-use std::sync::atomic::{AtomicU64, Ordering};
+use structopt::StructOpt;
 use tonic::transport::Channel;
 
-/// A helper to convert ethers Log to EthLogMetadata
-trait ToLogMeta {
-    fn to_log_meta(&self) -> EthLogMetadata;
+use fluidex_common::non_blocking_tracing;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "eth_listener",
+    about = "Dispatch fluidex contract events to the exchange"
+)]
+struct Opts {
+    /// Path of the config file, takes precedence over `LISTENER_CONFIG`
+    #[structopt(short, long)]
+    config: Option<String>,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-impl ToLogMeta for Log {
-    fn to_log_meta(&self) -> EthLogMetadata {
-        EthLogMetadata {
-            block_number: self.block_number.unwrap().as_u64(),
-            tx_hash: format!("{:#x}", self.transaction_hash.unwrap()),
-            log_index: format!("{:#x}", self.log_index.unwrap()),
-        }
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Listen on confirmed blocks and dispatch their events (default)
+    Run,
+    /// Process a historical block range without touching the persisted cursor
+    Backfill {
+        #[structopt(long)]
+        from: u64,
+        #[structopt(long)]
+        to: u64,
+    },
+    /// Re-dispatch the events of one transaction, except those already processed
+    Replay {
+        #[structopt(long)]
+        tx: H256,
+        /// re-dispatch the processed events too, the exchange dedupes deposits
+        #[structopt(long)]
+        force: bool,
+    },
+    /// Print the persisted cursor, chain head and lag
+    Status,
+    /// Print the decoded events of a block
+    Decode {
+        #[structopt(long)]
+        block: u64,
+    },
+    /// Print how far the deposits of a transaction got
+    Deposit {
+        #[structopt(long)]
+        tx: H256,
+    },
+    /// Compare the on-chain tokens with the exchange's assets and register the missing ones
+    Reconcile {
+        /// only report the differences
+        #[structopt(long)]
+        dry_run: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let opts = Opts::from_args();
+    if let Some(config) = &opts.config {
+        env::set_var("LISTENER_CONFIG", config);
     }
+
+    let guard = non_blocking_tracing::setup();
+
+    info!("{:?}", *CONFIG);
+    CONFIG.listener().apply_log_level();
+
+    let result = match opts.cmd.unwrap_or(Command::Run) {
+        Command::Run => run().await,
+        Command::Backfill { from, to } => backfill(from, to).await,
+        Command::Replay { tx, force } => replay(tx, force).await.map(|_| None),
+        Command::Status => status().await.map(|_| None),
+        Command::Decode { block } => decode(block).await.map(|_| None),
+        Command::Deposit { tx } => deposit(tx).await.map(|_| None),
+        Command::Reconcile { dry_run } => reconcile(dry_run).await.map(|_| None),
+    };
+    let code = match result {
+        Ok(None) => 0,
+        Ok(Some(signal)) => signal.exit_code(),
+        Err(e) => {
+            error!("{:?}", e);
+            1
+        }
+    };
+
+    // flush buffered logs, `process::exit` skips destructors
+    drop(guard);
+    std::process::exit(code);
 }
 
-fn get_business_id() -> u64 {
-    static BUSINESS_ID_SERIAL: AtomicU64 = AtomicU64::new(0);
-    BUSINESS_ID_SERIAL.fetch_add(1, Ordering::SeqCst)
+fn contract_address() -> Result<Address> {
+    Ok(CONFIG.web3().contract_address().parse()?)
 }
 
-use fluidex_common::non_blocking_tracing;
+/// Refuse to work against a websocket endpoint of another chain than configured.
+async fn verify_chain_id<P: JsonRpcClient>(provider: &Provider<P>) -> Result<()> {
+    metrics::rpc_call("eth_chainId");
+    let chain_id = provider.get_chainid().await?;
+    anyhow::ensure!(
+        chain_id == CONFIG.web3().chain_id().into(),
+        "websocket endpoint is on chain {}, expected {}",
+        chain_id,
+        CONFIG.web3().chain_id()
+    );
+    Ok(())
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let _guard = non_blocking_tracing::setup();
+async fn http_provider() -> Result<Arc<HttpProvider>> {
+    let mut client = FailoverClient::new(&CONFIG.web3().http_endpoints())?;
+    client.verify_chain_id(CONFIG.web3().chain_id()).await?;
+    // endpoints whose chain can't be checked were dropped, the rest must still reach the quorum
+    if let Some(quorum) = CONFIG.web3().quorum() {
+        anyhow::ensure!(
+            client.len() >= quorum,
+            "only {} http endpoints left after checking their chain, web3.quorum is {}",
+            client.len(),
+            quorum
+        );
+    }
+    Ok(Arc::new(Provider::new(client)))
+}
 
-    info!("{:?}", *CONFIG);
+/// Connect to the first websocket endpoint which answers and is on the configured chain.
+async fn ws_provider() -> Result<Provider<RateLimited<Ws>>> {
+    let mut last_error = anyhow::anyhow!("no websocket endpoint configured");
+    for url in CONFIG.web3().ws_endpoints() {
+        let connected = async {
+            let (ws, _) = tokio_tungstenite::connect_async(url.as_str()).await?;
+            let budget = rate_limit::budget_for(&provider::host_of(&url));
+            let ws_provider = Provider::new(RateLimited::new(Ws::new(ws), budget));
+            verify_chain_id(&ws_provider).await?;
+            Ok::<_, anyhow::Error>(ws_provider)
+        };
+        match connected.await {
+            Ok(ws_provider) => {
+                info!("subscribed through {}", provider::host_of(&url));
+                return Ok(ws_provider);
+            }
+            Err(e) => {
+                warn!(
+                    "websocket endpoint {} failed, failing over: {}",
+                    provider::host_of(&url),
+                    e
+                );
+                metrics::RECONNECTS.inc();
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
 
-    let inner_contract_address: Address = CONFIG.web3().inner_contract_address().parse().unwrap();
-    let (ws, _) = tokio_tungstenite::connect_async(CONFIG.web3().web3_ws()).await?;
-    let ws = Ws::new(ws);
-    let ws_provider = Arc::new(Provider::new(ws));
-    let http_provider = Arc::new(Provider::try_from(CONFIG.web3().web3_http())?);
+async fn build_processor(http_provider: Arc<HttpProvider>) -> Result<Processor> {
+    let inner_contract_address: Address = CONFIG.web3().inner_contract_address().parse()?;
     let grpc_channel = Channel::from_static(CONFIG.exchange().grpc_endpoint())
         .connect_timeout(Duration::from_secs(10))
         .connect()
         .await?;
-    let mut grpc_client = MatchengineClient::new(grpc_channel);
+    let grpc_client = MatchengineClient::new(grpc_channel);
+    HEALTH.set_grpc_connected(true);
     info!("grpc client ready");
 
     #[cfg(feature = "new_token")]
-    let rest_client = RestClient::new(CONFIG.exchange().rest_endpoint());
+    let rest_client = RestClient::new(
+        CONFIG.exchange().rest_endpoint(),
+        CONFIG.exchange().rest_options(),
+    )?;
     #[cfg(feature = "new_token")]
     info!("rest client ready");
 
-    let mut contract_infos =
-        ContractInfos::new(http_provider.clone(), inner_contract_address).await;
+    let contract_infos = ContractInfos::new(
+        http_provider.clone(),
+        inner_contract_address,
+        CONFIG.web3().chain_id() as i16,
+        CONFIG.web3().native_asset().to_erc20(),
+        config::current().tokens().clone(),
+    )
+    .await;
 
-    let persistor = Persistor::new(CONFIG.storage().db(), CONFIG.web3().base_block()).await?;
+    Ok(Processor::new(
+        http_provider,
+        contract_address()?,
+        grpc_client,
+        contract_infos,
+        #[cfg(feature = "new_token")]
+        rest_client,
+    ))
+}
+
+async fn open_persistor() -> Result<Persistor> {
+    Ok(Persistor::new(
+        &CONFIG.storage().db(),
+        &CONFIG.storage().pool_options(),
+        CONFIG.web3().base_block(),
+    )
+    .await?)
+}
+
+/// A processor for blocks out of order, it shares the persisted state of `run` but not its
+/// cursor.
+async fn build_detached_processor() -> Result<(Processor, Arc<Persistor>)> {
+    let mut processor = build_processor(http_provider().await?).await?;
+    let persistor = Arc::new(open_persistor().await?);
+    processor.seed_tokens(persistor.load_tokens().await?);
+    processor.set_persistor(persistor.clone());
+    Ok((processor, persistor))
+}
+
+/// Dispatch the `events` of block `block_number` and persist the tokens and held deposits
+/// they yield, leaving the cursor alone.
+async fn process_detached(
+    processor: &mut Processor,
+    persistor: &Persistor,
+    block_number: u64,
+    events: Vec<Events>,
+) -> Result<()> {
+    let mut record = BlockRecord::detached(block_number, &events);
+    processor.process_events(events).await?;
+    record.tokens = processor.take_token_updates();
+    record.pending_deposits = processor.take_pending_deposits();
+    persistor.commit_block(&record).await?;
+    Ok(())
+}
+
+/// Feed `DEPOSITS` with the deposits of every new head block, reconnecting on failures.
+async fn track_pending_deposits(http_provider: Arc<HttpProvider>) {
+    loop {
+        let tracked = async {
+            let ws_provider = ws_provider().await?;
+            metrics::rpc_call("eth_subscribe");
+            let mut heads = ws_provider.subscribe_blocks().await?;
+            while let Some(block) = heads.next().await {
+                let (number, hash) = match (block.number, block.hash) {
+                    (Some(number), Some(hash)) => (number.as_u64(), hash),
+                    _ => continue,
+                };
+                let events =
+                    processor::fetch_events(&http_provider, contract_address()?, number, number)
+                        .await?;
+                let deposits: Vec<_> = events
+                    .into_iter()
+                    .filter_map(|event| match event {
+                        Events::Deposit(deposit) => Some(deposit),
+                        _ => None,
+                    })
+                    .collect();
+                DEPOSITS.observe_block(number, hash, &deposits);
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        if let Err(e) = tracked.await {
+            warn!("pending deposit tracker failed: {}", e);
+        }
+        metrics::RECONNECTS.inc();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Returns the signal which stopped the listener, if any.
+async fn run() -> Result<Option<Signal>> {
+    let mut shutdown = Shutdown::install()?;
+
+    // bound before spawning, a listener without its probes must not start
+    let server = server::serve(CONFIG.http().listen().parse()?)?;
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("http server error: {}", e);
+        }
+    });
+    tokio::spawn(async {
+        if let Err(e) = config::watch().await {
+            error!("config watcher error: {}", e);
+        }
+    });
+
+    let http_provider = http_provider().await?;
+    let mut processor = build_processor(http_provider.clone()).await?;
+
+    let persistor = Arc::new(open_persistor().await?);
+    processor.seed_tokens(persistor.load_tokens().await?);
+    processor.set_persistor(persistor.clone());
     info!("persistor ready");
 
+    let probed = persistor.clone();
+    HEALTH.set_db_probe(Box::new(move || {
+        let persistor = probed.clone();
+        Box::pin(async move { persistor.ping().await.is_ok() })
+    }));
+    HEALTH.set_grpc_probe(Box::new(|| {
+        Box::pin(async {
+            Channel::from_static(CONFIG.exchange().grpc_endpoint())
+                .connect()
+                .await
+                .is_ok()
+        })
+    }));
+
+    if CONFIG.exchange().reconcile_assets() {
+        // without `new_token` the exchange assets are managed elsewhere, only report
+        let dry_run = !cfg!(feature = "new_token");
+        let rest_client = RestClient::new(
+            CONFIG.exchange().rest_endpoint(),
+            CONFIG.exchange().rest_options(),
+        )?;
+        // a report, not a precondition: the listener runs with the exchange out of sync
+        if let Err(e) =
+            reconcile_assets(processor.contract_infos_mut(), &rest_client, dry_run).await
+        {
+            error!("asset reconciliation failed: {:?}", e);
+        }
+    }
+
+    if CONFIG.http().pending_deposits() {
+        tokio::spawn(track_pending_deposits(http_provider.clone()));
+    }
+
     info!("start listening on eth net");
 
-    let mut confirmed_stream =
-        ConfirmedBlockStream::new(&ws_provider, persistor.get_block_number().await?, 3).await?;
+    let ws_provider = ws_provider().await?;
+    let cursor = tokio::select! {
+        signal = shutdown.recv() => return Ok(Some(signal)),
+        cursor = persistor.get_block_number() => cursor?,
+    };
+    let stream = ConfirmedBlockStream::new(&ws_provider, cursor, CONFIG.web3().finality());
+    let mut confirmed_stream = tokio::select! {
+        signal = shutdown.recv() => return Ok(Some(signal)),
+        stream = stream => stream?,
+    };
 
-    while let Some(block) = confirmed_stream.next().await {
-        let block = block?;
-        let block_number = block.number.unwrap();
+    loop {
+        let block = tokio::select! {
+            signal = shutdown.recv() => return Ok(Some(signal)),
+            block = confirmed_stream.next() => block,
+        };
+        let block = match block {
+            Some(block) => block?,
+            // not a shutdown, the supervisor has to restart the listener
+            None => anyhow::bail!("block subscription ended"),
+        };
+        let block_number = block.number.unwrap().as_u64();
+        let live_config = config::current();
+        confirmed_stream.set_finality(live_config.web3().finality());
+        processor
+            .contract_infos_mut()
+            .set_tokens(live_config.tokens().clone());
+        metrics::rpc_call("eth_blockNumber");
+        let head = tokio::select! {
+            signal = shutdown.recv() => return Ok(Some(signal)),
+            head = http_provider.get_block_number() => head?.as_u64(),
+        };
         info!(
             "current: {}, confirmed: {} {:?}",
-            http_provider.get_block_number().await?.as_u64(),
+            head,
             block_number,
             block.hash.unwrap()
         );
-        let log_filter = Filter::default()
-            .from_block(block_number)
-            .to_block(block_number)
-            .address(ValueOrArray::Value(
-                CONFIG.web3().contract_address().parse::<Address>()?,
-            ));
-        let events = http_provider
-            .get_logs(&log_filter)
-            .await?
-            .into_iter()
-            .map(|log| Events::try_from(log).ok())
-            .filter(Option::is_some)
-            .map(Option::unwrap)
-            .collect::<Vec<Events>>();
-        for event in events {
-            info!("process event: {:?}", event);
-            match event {
-                Events::Deposit(deposit) => {
-                    let user_id = contract_infos.fetch_user_id(&deposit.to).await?;
-                    let mut delta = Decimal::from_str(deposit.amount.to_string().as_str())?;
-                    if deposit.token_id == 0 {
-                        // we are dealing with an ETH deposit request
-                        // 1 ETH = 10^18 wei
-                        delta.set_scale(18)?;
-                        grpc_client
-                            .balance_update(BalanceUpdateRequest {
-                                user_id: user_id as u32,
-                                asset: "ETH".to_string(),
-                                business: "deposit".to_string(),
-                                business_id: get_business_id(),
-                                delta: format!("{}", delta),
-                                detail: "".to_string(),
-                                signature: Some("".to_string()),
-                                log_metadata: Some(deposit.origin.to_log_meta()),
-                            })
-                            .await?;
-                    } else {
-                        // we are dealing with an ERC20 deposit request
-                        let address = contract_infos.fetch_token_address(deposit.token_id).await?;
-                        let erc20 = contract_infos.fetch_erc20(address).await;
-                        delta.set_scale(erc20.decimals as u32)?;
-                        grpc_client
-                            .balance_update(BalanceUpdateRequest {
-                                user_id: user_id as u32,
-                                asset: erc20.symbol,
-                                business: "deposit".to_string(),
-                                business_id: get_business_id(),
-                                delta: format!("{}", delta),
-                                detail: "".to_string(),
-                                signature: Some("".to_string()),
-                                log_metadata: Some(deposit.origin.to_log_meta()),
-                            })
-                            .await?;
-                    }
-                }
-                #[cfg(feature = "new_token")]
-                Events::NewToken(new_token) => {
-                    let asset = contract_infos
-                        .add_token(new_token.token_addr, new_token.token_id)
-                        .await;
-                    rest_client
-                        .add_assets(&NewAssetReq {
-                            assets: vec![asset],
-                            not_reload: false,
-                        })
-                        .await?;
-                }
-                Events::RegisterUser(register_user) => {
-                    grpc_client
-                        .register_user(UserInfo {
-                            user_id: register_user.user_id as u32,
-                            l1_address: register_user.eth_addr.to_string(),
-                            l2_pubkey: hex::encode(register_user.bjj_pubkey),
-                            log_metadata: Some(register_user.origin.to_log_meta()),
-                        })
-                        .await?;
-                }
-                _ => {
-                    warn!("ignoring {:?}", event);
-                }
+
+        // Large deposits wait for extra confirmations, which holds back the later blocks
+        // as well. The logs are fetched again after the wait, the block may have been
+        // reorged meanwhile and only its canonical logs may be credited.
+        let mut events = tokio::select! {
+            signal = shutdown.recv() => return Ok(Some(signal)),
+            events = processor.fetch_block(block_number) => events?,
+        };
+        let mut waited = 0;
+        loop {
+            let confirmations = processor::required_confirmations(&events);
+            if confirmations <= waited {
+                break;
+            }
+            let wait = processor::wait_confirmations(&http_provider, block_number, confirmations);
+            tokio::select! {
+                signal = shutdown.recv() => return Ok(Some(signal)),
+                result = wait => result?,
+            }
+            waited = confirmations;
+            events = tokio::select! {
+                signal = shutdown.recv() => return Ok(Some(signal)),
+                events = processor.fetch_block(block_number) => events?,
+            };
+        }
+
+        // finish the in-flight block and persist its cursor before honouring a shutdown
+        let commit = async {
+            let mut record = BlockRecord::new(block_number, &events);
+            let tracked = if CONFIG.http().pending_deposits() {
+                events.clone()
+            } else {
+                Vec::new()
+            };
+            processor.process_events(events).await?;
+            record.tokens = processor.take_token_updates();
+            record.pending_deposits = processor.take_pending_deposits();
+            persistor.commit_block(&record).await?;
+            // only once committed, a failed block is processed again
+            if CONFIG.http().pending_deposits() {
+                DEPOSITS.confirm_block(block_number, &tracked, &record.pending_deposits);
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        tokio::pin!(commit);
+        tokio::select! {
+            result = &mut commit => result?,
+            signal = shutdown.recv() => {
+                info!("draining block#{} before exit", block_number);
+                tokio::time::timeout(live_config.listener().drain_timeout(), commit)
+                    .await
+                    .map_err(|_| {
+                        anyhow::anyhow!("drain timeout exceeded on block#{}", block_number)
+                    })??;
+                return Ok(Some(signal));
+            }
+        }
+        metrics::CONFIRMED_BLOCK.set(block_number as i64);
+        metrics::BLOCK_LAG.set(head.saturating_sub(block_number) as i64);
+    }
+}
+
+/// Returns the signal which stopped the backfill, if any, the blocks before are processed.
+async fn backfill(from: u64, to: u64) -> Result<Option<Signal>> {
+    anyhow::ensure!(from <= to, "invalid block range [{}, {}]", from, to);
+    let mut shutdown = Shutdown::install()?;
+    let (mut processor, persistor) = build_detached_processor().await?;
+    for block_number in from..=to {
+        info!("backfill block#{}", block_number);
+        let events = tokio::select! {
+            signal = shutdown.recv() => {
+                info!("backfill stopped before block#{}", block_number);
+                return Ok(Some(signal));
             }
+            events = processor.fetch_block(block_number) => events?,
+        };
+        process_detached(&mut processor, &persistor, block_number, events).await?;
+    }
+    Ok(None)
+}
+
+async fn reconcile(dry_run: bool) -> Result<()> {
+    let mut processor = build_processor(http_provider().await?).await?;
+    let rest_client = RestClient::new(
+        CONFIG.exchange().rest_endpoint(),
+        CONFIG.exchange().rest_options(),
+    )?;
+    let report = reconcile_assets(processor.contract_infos_mut(), &rest_client, dry_run).await?;
+    println!("checked:    {}", report.checked);
+    println!("missing:    {}", report.missing.len());
+    println!("mismatches: {}", report.mismatches.len());
+    for mismatch in &report.mismatches {
+        println!("  {}", mismatch);
+    }
+    println!("unresolved: {:?}", report.unresolved);
+    Ok(())
+}
+
+async fn replay(tx: H256, force: bool) -> Result<()> {
+    let (mut processor, persistor) = build_detached_processor().await?;
+    processor.set_force(force);
+    info!("replay transaction {:#x}", tx);
+    let events = processor.fetch_tx(tx).await?;
+    let block_number = match events.first() {
+        Some(event) => event.origin().block_number.unwrap_or_default().as_u64(),
+        None => {
+            println!("no contract event in transaction {:#x}", tx);
+            return Ok(());
+        }
+    };
+    let mut skipped = 0;
+    for event in &events {
+        if processor.is_processed(&ProcessedEvent::from(event)).await? {
+            skipped += 1;
         }
-        persistor.save_block_number(block_number.as_u64()).await?;
     }
+    let total = events.len();
+    process_detached(&mut processor, &persistor, block_number, events).await?;
+    println!("replayed: {} of {} events", total - skipped, total);
+    if skipped > 0 {
+        println!(
+            "skipped:  {} already processed, --force dispatches them again",
+            skipped
+        );
+    }
+    Ok(())
+}
+
+async fn status() -> Result<()> {
+    let persistor = open_persistor().await?;
+    let cursor = persistor.get_block_number().await?;
+    let head = http_provider().await?.get_block_number().await?.as_u64();
+    println!("cursor: {}", cursor);
+    println!("head:   {}", head);
+    println!("lag:    {}", head.saturating_sub(cursor));
+    println!(
+        "pending deposits: {}",
+        persistor.load_pending_deposits().await?.len()
+    );
+    Ok(())
+}
+
+async fn deposit(tx: H256) -> Result<()> {
+    let persistor = open_persistor().await?;
+    let records = persistor.load_deposits(&format!("{:#x}", tx)).await?;
+    anyhow::ensure!(!records.is_empty(), "no deposit recorded for {:#x}", tx);
+    for record in records {
+        println!("log index: {}", record.log_index);
+        println!("  block:   {}", record.block_number);
+        println!("  user:    {:?}", record.user_id);
+        println!("  token:   #{} {:?}", record.token_id, record.asset);
+        println!("  amount:  {} (credited {:?})", record.amount, record.delta);
+        println!("  state:   {}", record.state.as_str());
+        if let Some(error) = record.error {
+            println!("  error:   {}", error);
+        }
+    }
+    Ok(())
+}
 
+async fn decode(block: u64) -> Result<()> {
+    let events =
+        processor::fetch_events(&*http_provider().await?, contract_address()?, block, block)
+            .await?;
+    for event in events {
+        println!("{}", serde_json::to_string_pretty(&event)?);
+    }
     Ok(())
 }
@@ -0,0 +1,148 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+pub static CONFIRMED_BLOCK: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "eth_listener_confirmed_block",
+        "last confirmed block whose events are dispatched"
+    )
+    .unwrap()
+});
+
+pub static CHAIN_HEAD: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("eth_listener_chain_head", "newest block observed on chain").unwrap()
+});
+
+pub static BLOCK_LAG: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "eth_listener_block_lag",
+        "blocks between the chain head and the last confirmed block"
+    )
+    .unwrap()
+});
+
+pub static EVENTS_PROCESSED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "eth_listener_events_processed_total",
+        "contract events processed, by event type",
+        &["event"]
+    )
+    .unwrap()
+});
+
+pub static EVENTS_QUARANTINED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "eth_listener_events_quarantined_total",
+        "contract events skipped because of a quarantined token, by event type",
+        &["event"]
+    )
+    .unwrap()
+});
+
+pub static DEPOSITS_HELD: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "eth_listener_deposits_held_total",
+        "deposits of unlisted tokens held instead of credited"
+    )
+    .unwrap()
+});
+
+pub static DEPOSITS_TRUNCATED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "eth_listener_deposits_truncated_total",
+        "deposits credited without the dust below the kept precision, by asset",
+        &["asset"]
+    )
+    .unwrap()
+});
+
+pub static GRPC_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "eth_listener_grpc_latency_seconds",
+        "latency of gRPC calls to the exchange, by method",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static GRPC_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "eth_listener_grpc_errors_total",
+        "failed gRPC calls to the exchange, by method",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static INFOS_CACHE: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "eth_listener_infos_cache_total",
+        "ContractInfos cache lookups, by cache and result (hit/miss)",
+        &["cache", "result"]
+    )
+    .unwrap()
+});
+
+pub static RPC_CALLS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "eth_listener_rpc_calls_total",
+        "JSON-RPC calls to ethereum nodes, by method",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static RECONNECTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "eth_listener_reconnects_total",
+        "websocket reconnects, to the next endpoint or after a subscription failed"
+    )
+    .unwrap()
+});
+
+pub static RPC_THROTTLED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "eth_listener_rpc_throttled_total",
+        "JSON-RPC calls throttled by ethereum nodes, by method",
+        &["method"]
+    )
+    .unwrap()
+});
+
+/// Register all metrics so they are exported before their first update.
+pub fn init() {
+    Lazy::force(&CONFIRMED_BLOCK);
+    Lazy::force(&CHAIN_HEAD);
+    Lazy::force(&BLOCK_LAG);
+    Lazy::force(&EVENTS_PROCESSED);
+    Lazy::force(&EVENTS_QUARANTINED);
+    Lazy::force(&DEPOSITS_HELD);
+    Lazy::force(&DEPOSITS_TRUNCATED);
+    Lazy::force(&GRPC_LATENCY);
+    Lazy::force(&GRPC_ERRORS);
+    Lazy::force(&INFOS_CACHE);
+    Lazy::force(&RPC_CALLS);
+    Lazy::force(&RECONNECTS);
+    Lazy::force(&RPC_THROTTLED);
+}
+
+pub fn rpc_call(method: &str) {
+    RPC_CALLS.with_label_values(&[method]).inc();
+}
+
+pub fn cache_lookup(cache: &str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    INFOS_CACHE.with_label_values(&[cache, result]).inc();
+}
+
+/// Encode all registered metrics in the prometheus text format.
+pub fn gather() -> Vec<u8> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    buffer
+}
@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Mutex;
+
+use ethers::types::H256;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::events::{Deposit, Events};
+use crate::persist::PendingDeposit;
+
+pub static DEPOSITS: Lazy<DepositTracker> = Lazy::new(DepositTracker::new);
+
+/// Blocks behind the head after which resolved deposits are forgotten.
+const RETENTION_BLOCKS: u64 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DepositStatus {
+    /// seen at the chain head, not credited yet
+    Pending,
+    /// its block is confirmed and committed, the listener credited it
+    Confirmed,
+    /// its block is confirmed, but the listener holds it instead of crediting it
+    Held,
+    /// its block was reorged out
+    Dropped,
+}
+
+/// A deposit as shown to users before and after it is credited.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackedDeposit {
+    pub tx_hash: String,
+    pub log_index: u64,
+    pub block_number: u64,
+    pub block_hash: String,
+    pub token_id: u16,
+    /// public key of the receiving account
+    pub to: String,
+    /// raw amount in the token's smallest unit, as a decimal string
+    pub amount: String,
+    pub confirmations: u64,
+    pub status: DepositStatus,
+}
+
+type DepositKey = (String, u64);
+
+impl TrackedDeposit {
+    fn new(deposit: &Deposit, status: DepositStatus) -> Self {
+        let origin = &deposit.origin;
+        Self {
+            tx_hash: format!("{:#x}", origin.transaction_hash.unwrap_or_default()),
+            log_index: origin.log_index.unwrap_or_default().as_u64(),
+            block_number: origin.block_number.unwrap_or_default().as_u64(),
+            block_hash: format!("{:#x}", origin.block_hash.unwrap_or_default()),
+            token_id: deposit.token_id,
+            to: format!("0x{}", hex::encode(deposit.to)),
+            amount: deposit.amount.to_string(),
+            confirmations: 0,
+            status,
+        }
+    }
+
+    fn key(&self) -> DepositKey {
+        (self.tx_hash.clone(), self.log_index)
+    }
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    head: u64,
+    /// hashes of the recent head blocks, to notice reorgs
+    blocks: BTreeMap<u64, H256>,
+    deposits: BTreeMap<DepositKey, TrackedDeposit>,
+}
+
+impl TrackerState {
+    fn refresh_confirmations(&mut self) {
+        let head = self.head;
+        for deposit in self.deposits.values_mut() {
+            if deposit.status != DepositStatus::Dropped {
+                deposit.confirmations = (head + 1).saturating_sub(deposit.block_number);
+            }
+        }
+    }
+
+    fn prune(&mut self) {
+        let horizon = self.head.saturating_sub(RETENTION_BLOCKS);
+        self.blocks = self.blocks.split_off(&horizon);
+        self.deposits.retain(|_, deposit| {
+            deposit.status == DepositStatus::Pending || deposit.block_number >= horizon
+        });
+    }
+}
+
+/// Deposits decoded from the chain head, until their block is confirmed or reorged out.
+#[derive(Debug)]
+pub struct DepositTracker {
+    state: Mutex<TrackerState>,
+}
+
+impl DepositTracker {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(TrackerState::default()),
+        }
+    }
+
+    /// Record the head block `number` and the deposits decoded from it.
+    pub fn observe_block(&self, number: u64, hash: H256, deposits: &[Deposit]) {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .blocks
+            .get(&number)
+            .map_or(false, |known| *known != hash)
+        {
+            info!("block#{} reorged to {:#x}", number, hash);
+            state.blocks.retain(|block, _| *block < number);
+            for deposit in state.deposits.values_mut() {
+                if deposit.status == DepositStatus::Pending && deposit.block_number >= number {
+                    deposit.status = DepositStatus::Dropped;
+                }
+            }
+        }
+        state.blocks.insert(number, hash);
+        for deposit in deposits {
+            if deposit.origin.block_hash != Some(hash) {
+                continue;
+            }
+            let tracked = TrackedDeposit::new(deposit, DepositStatus::Pending);
+            let key = tracked.key();
+            match state.deposits.get(&key) {
+                // settled by a committed block, head blocks don't change it anymore
+                Some(known)
+                    if matches!(known.status, DepositStatus::Confirmed | DepositStatus::Held) => {}
+                _ => {
+                    state.deposits.insert(key, tracked);
+                }
+            }
+        }
+        state.head = state.head.max(number);
+        state.refresh_confirmations();
+        state.prune();
+    }
+
+    /// Settle the deposits of the committed block `number`, those of `events` are confirmed,
+    /// or held if in `held`, and any other one still pending at or below `number` was reorged
+    /// out.
+    pub fn confirm_block(&self, number: u64, events: &[Events], held: &[PendingDeposit]) {
+        let mut state = self.state.lock().unwrap();
+        let held: HashSet<DepositKey> = held
+            .iter()
+            .map(|deposit| (deposit.tx_hash.clone(), deposit.log_index))
+            .collect();
+        let mut confirmed = HashSet::new();
+        for event in events {
+            if let Events::Deposit(deposit) = event {
+                let mut tracked = TrackedDeposit::new(deposit, DepositStatus::Confirmed);
+                if held.contains(&tracked.key()) {
+                    tracked.status = DepositStatus::Held;
+                }
+                confirmed.insert(tracked.key());
+                state.deposits.insert(tracked.key(), tracked);
+            }
+        }
+        for (key, deposit) in state.deposits.iter_mut() {
+            if deposit.status == DepositStatus::Pending
+                && deposit.block_number <= number
+                && !confirmed.contains(key)
+            {
+                deposit.status = DepositStatus::Dropped;
+            }
+        }
+        state.head = state.head.max(number);
+        state.refresh_confirmations();
+        state.prune();
+    }
+
+    /// The tracked deposits, only those to the account `to` if given.
+    pub fn snapshot(&self, to: Option<&str>) -> Vec<TrackedDeposit> {
+        let state = self.state.lock().unwrap();
+        state
+            .deposits
+            .values()
+            .filter(|deposit| to.map_or(true, |to| deposit.to.eq_ignore_ascii_case(to)))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for DepositTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Address, Log};
+
+    fn deposit(block_number: u64, block_hash: H256, tx: u64) -> Deposit {
+        Deposit {
+            token_id: 1,
+            to: [7; 32],
+            amount: 1_000_000,
+            origin: Log {
+                block_number: Some(block_number.into()),
+                block_hash: Some(block_hash),
+                transaction_hash: Some(H256::from_low_u64_be(tx)),
+                log_index: Some(0.into()),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn status(tracker: &DepositTracker, tx: u64) -> (DepositStatus, u64) {
+        let tx_hash = format!("{:#x}", H256::from_low_u64_be(tx));
+        let deposit = tracker
+            .snapshot(None)
+            .into_iter()
+            .find(|deposit| deposit.tx_hash == tx_hash)
+            .unwrap();
+        (deposit.status, deposit.confirmations)
+    }
+
+    #[test]
+    fn test_tracker() {
+        let tracker = DepositTracker::new();
+        let (a, b, b2, c) = (
+            H256::from_low_u64_be(10),
+            H256::from_low_u64_be(11),
+            H256::from_low_u64_be(111),
+            H256::from_low_u64_be(12),
+        );
+        tracker.observe_block(10, a, &[deposit(10, a, 1)]);
+        tracker.observe_block(11, b, &[deposit(11, b, 2)]);
+        assert_eq!((DepositStatus::Pending, 2), status(&tracker, 1));
+        let to = format!("0x{}", "07".repeat(32));
+        assert_eq!(2, tracker.snapshot(Some(&to)).len());
+        assert!(tracker.snapshot(Some("0x01")).is_empty());
+
+        // block 11 is replaced, its deposit moves to block 12
+        tracker.observe_block(11, b2, &[]);
+        assert_eq!(DepositStatus::Dropped, status(&tracker, 2).0);
+        tracker.observe_block(12, c, &[deposit(12, c, 2)]);
+        assert_eq!((DepositStatus::Pending, 1), status(&tracker, 2));
+
+        tracker.confirm_block(10, &[Events::Deposit(deposit(10, a, 1))], &[]);
+        assert_eq!((DepositStatus::Confirmed, 3), status(&tracker, 1));
+        tracker.confirm_block(12, &[], &[]);
+        assert_eq!(DepositStatus::Dropped, status(&tracker, 2).0);
+
+        let held = PendingDeposit {
+            tx_hash: format!("{:#x}", H256::from_low_u64_be(3)),
+            log_index: 0,
+            block_number: 13,
+            token_id: 1,
+            token_address: format!("{:#x}", Address::zero()),
+            user_id: 1,
+            amount: 1_000_000,
+        };
+        let d = H256::from_low_u64_be(13);
+        tracker.confirm_block(13, &[Events::Deposit(deposit(13, d, 3))], &[held]);
+        assert_eq!(DepositStatus::Held, status(&tracker, 3).0);
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use super::{
+    BlockRecord, CursorStore, DepositRecord, PendingDeposit, ProcessedEvent, Result, TokenRecord,
+};
+
+#[derive(Debug, Default)]
+struct State {
+    block_number: Option<u64>,
+    blocks: HashSet<u64>,
+    events: HashSet<(String, u64)>,
+    tokens: BTreeMap<u16, TokenRecord>,
+    pending_deposits: Vec<PendingDeposit>,
+    deposits: BTreeMap<(String, u64), DepositRecord>,
+}
+
+/// Keeps the cursor in memory only, for development and tests.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+#[async_trait]
+impl CursorStore for MemoryStore {
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn load_block_number(&self) -> Result<Option<u64>> {
+        Ok(self.state.lock().unwrap().block_number)
+    }
+
+    async fn commit_block(&self, record: &BlockRecord) -> Result<()> {
+        // the lock makes the commit atomic
+        let mut state = self.state.lock().unwrap();
+        if record.advance_cursor && state.blocks.insert(record.block_number) {
+            state.block_number = Some(record.block_number);
+        }
+        for event in &record.events {
+            state
+                .events
+                .insert((event.tx_hash.clone(), event.log_index));
+        }
+        for token in &record.tokens {
+            state.tokens.insert(token.token_id, token.clone());
+        }
+        for deposit in &record.pending_deposits {
+            let held = state
+                .pending_deposits
+                .iter()
+                .any(|d| d.tx_hash == deposit.tx_hash && d.log_index == deposit.log_index);
+            if !held {
+                state.pending_deposits.push(deposit.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn is_processed(&self, tx_hash: &str, log_index: u64) -> Result<bool> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .events
+            .contains(&(tx_hash.to_string(), log_index)))
+    }
+
+    async fn mark_processed(&self, _block_number: u64, event: &ProcessedEvent) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .events
+            .insert((event.tx_hash.clone(), event.log_index));
+        Ok(())
+    }
+
+    async fn load_tokens(&self) -> Result<Vec<TokenRecord>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .tokens
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    async fn load_pending_deposits(&self) -> Result<Vec<PendingDeposit>> {
+        Ok(self.state.lock().unwrap().pending_deposits.clone())
+    }
+
+    async fn save_deposit(&self, record: &DepositRecord) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let key = (record.tx_hash.clone(), record.log_index);
+        let mut record = record.clone();
+        if let Some(known) = state.deposits.get(&key) {
+            // like the sql backends, a missing value doesn't erase a known one
+            record.user_id = record.user_id.or(known.user_id);
+            record.asset = record.asset.or_else(|| known.asset.clone());
+            record.delta = record.delta.or_else(|| known.delta.clone());
+        }
+        state.deposits.insert(key, record);
+        Ok(())
+    }
+
+    async fn load_deposits(&self, tx_hash: &str) -> Result<Vec<DepositRecord>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .deposits
+            .values()
+            .filter(|record| record.tx_hash == tx_hash)
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,497 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::events::{Deposit, Events};
+use crate::health::HEALTH;
+
+mod memory;
+mod postgres;
+mod sqlite;
+
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PersistorError {
+    #[error("persistor error occurred from postgres: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("no postgres connection available: {0}")]
+    Pool(#[from] deadpool_postgres::PoolError),
+    #[error("failed to build the postgres pool: {0}")]
+    PoolBuild(String),
+    #[error("failed to set up postgres tls: {0}")]
+    Tls(String),
+    #[error("postgres query timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("persistor error occurred from sqlite: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("database schema is at version {0}, newer than the latest known version {1}")]
+    SchemaTooNew(i32, i32),
+    #[error("invalid amount {0:?} in the database")]
+    InvalidAmount(String),
+    #[error("invalid deposit state {0:?} in the database")]
+    InvalidState(String),
+    #[error("unsupported storage dsn, expected postgres, sqlite or memory: {0}")]
+    UnsupportedDsn(String),
+}
+
+type Result<T, E = PersistorError> = std::result::Result<T, E>;
+
+/// An event dispatched to the exchange.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessedEvent {
+    pub tx_hash: String,
+    pub log_index: u64,
+    pub event: &'static str,
+}
+
+impl From<&Events> for ProcessedEvent {
+    fn from(event: &Events) -> Self {
+        let origin = event.origin();
+        Self {
+            tx_hash: format!("{:#x}", origin.transaction_hash.unwrap_or_default()),
+            log_index: origin.log_index.unwrap_or_default().as_u64(),
+            event: event.name(),
+        }
+    }
+}
+
+/// A token learned from the contract, cached across restarts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenRecord {
+    pub token_id: u16,
+    pub address: String,
+    pub symbol: String,
+    pub name: String,
+    pub decimals: u8,
+}
+
+/// A deposit of an unlisted token, held instead of credited.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingDeposit {
+    pub tx_hash: String,
+    pub log_index: u64,
+    pub block_number: u64,
+    pub token_id: u16,
+    pub token_address: String,
+    pub user_id: u16,
+    /// raw amount in the token's smallest unit
+    pub amount: u128,
+}
+
+/// Steps of a deposit through the listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepositState {
+    /// decoded from a confirmed block
+    Seen,
+    UserResolved,
+    TokenResolved,
+    /// not credited, see `PendingDeposit`
+    Held,
+    /// `balance_update` sent to the exchange
+    Sent,
+    /// `balance_update` accepted by the exchange
+    Acknowledged,
+    Failed,
+}
+
+impl DepositState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DepositState::Seen => "seen",
+            DepositState::UserResolved => "user_resolved",
+            DepositState::TokenResolved => "token_resolved",
+            DepositState::Held => "held",
+            DepositState::Sent => "sent",
+            DepositState::Acknowledged => "acknowledged",
+            DepositState::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for DepositState {
+    type Err = PersistorError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let state = match s {
+            "seen" => DepositState::Seen,
+            "user_resolved" => DepositState::UserResolved,
+            "token_resolved" => DepositState::TokenResolved,
+            "held" => DepositState::Held,
+            "sent" => DepositState::Sent,
+            "acknowledged" => DepositState::Acknowledged,
+            "failed" => DepositState::Failed,
+            _ => return Err(PersistorError::InvalidState(s.to_string())),
+        };
+        Ok(state)
+    }
+}
+
+/// A deposit and how far the listener got with it, saved at every step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepositRecord {
+    pub tx_hash: String,
+    pub log_index: u64,
+    pub block_number: u64,
+    pub user_id: Option<u16>,
+    pub token_id: u16,
+    /// symbol of the credited asset, once the token is resolved
+    pub asset: Option<String>,
+    /// raw amount in the token's smallest unit
+    pub amount: u128,
+    /// amount credited on the exchange, once scaled
+    pub delta: Option<String>,
+    pub state: DepositState,
+    /// why the deposit is held or failed
+    pub error: Option<String>,
+}
+
+impl From<&Deposit> for DepositRecord {
+    fn from(deposit: &Deposit) -> Self {
+        let origin = &deposit.origin;
+        Self {
+            tx_hash: format!("{:#x}", origin.transaction_hash.unwrap_or_default()),
+            log_index: origin.log_index.unwrap_or_default().as_u64(),
+            block_number: origin.block_number.unwrap_or_default().as_u64(),
+            user_id: None,
+            token_id: deposit.token_id,
+            asset: None,
+            amount: deposit.amount,
+            delta: None,
+            state: DepositState::Seen,
+            error: None,
+        }
+    }
+}
+
+/// Everything derived from a block, committed together with the cursor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockRecord {
+    pub block_number: u64,
+    /// false for blocks processed out of order, e.g. by a backfill
+    pub advance_cursor: bool,
+    pub events: Vec<ProcessedEvent>,
+    pub tokens: Vec<TokenRecord>,
+    pub pending_deposits: Vec<PendingDeposit>,
+}
+
+impl BlockRecord {
+    pub fn new(block_number: u64, events: &[Events]) -> Self {
+        Self {
+            block_number,
+            advance_cursor: true,
+            events: events.iter().map(ProcessedEvent::from).collect(),
+            tokens: Vec::new(),
+            pending_deposits: Vec::new(),
+        }
+    }
+
+    /// A record committed without moving the cursor to `block_number`.
+    pub fn detached(block_number: u64, events: &[Events]) -> Self {
+        Self {
+            advance_cursor: false,
+            ..Self::new(block_number, events)
+        }
+    }
+}
+
+/// Where the cursor of the last processed block and its derived state are kept.
+#[async_trait]
+pub trait CursorStore: Send + Sync {
+    /// Fail unless the storage answers a trivial query.
+    async fn ping(&self) -> Result<()>;
+    /// The last committed block, `None` if nothing was committed yet.
+    async fn load_block_number(&self) -> Result<Option<u64>>;
+    /// Commit the cursor, unless `advance_cursor` is false, events and tokens of a block
+    /// atomically.
+    /// Committing a block again is a no-op, except tokens which are overwritten.
+    async fn commit_block(&self, record: &BlockRecord) -> Result<()>;
+    /// Whether the event at `log_index` of transaction `tx_hash` was dispatched already.
+    async fn is_processed(&self, tx_hash: &str, log_index: u64) -> Result<bool>;
+    /// Record an event as dispatched as soon as it is, ahead of the commit of its block.
+    async fn mark_processed(&self, block_number: u64, event: &ProcessedEvent) -> Result<()>;
+    async fn load_tokens(&self) -> Result<Vec<TokenRecord>>;
+    async fn load_pending_deposits(&self) -> Result<Vec<PendingDeposit>>;
+    /// Insert or update the record of a deposit, outside of any block commit.
+    /// `user_id`, `asset` and `delta` are only overwritten by known values.
+    async fn save_deposit(&self, record: &DepositRecord) -> Result<()>;
+    /// Records of the deposits of transaction `tx_hash`.
+    async fn load_deposits(&self, tx_hash: &str) -> Result<Vec<DepositRecord>>;
+}
+
+/// The storage backend selected by the scheme of `storage.db`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Dsn {
+    /// `postgres://…`, `postgresql://…` or `host=… user=…`
+    Postgres(String),
+    /// `sqlite://path/to/file.db` or `sqlite::memory:`
+    Sqlite(String),
+    /// `memory:`, the cursor is lost on exit
+    Memory,
+}
+
+impl Dsn {
+    pub fn parse(db: &str) -> Result<Self> {
+        if db == "memory:" {
+            Ok(Dsn::Memory)
+        } else if let Some(path) = db.strip_prefix("sqlite:") {
+            let path = path.trim_start_matches("//");
+            if path.is_empty() {
+                return Err(PersistorError::UnsupportedDsn(
+                    "missing sqlite path".to_string(),
+                ));
+            }
+            Ok(Dsn::Sqlite(path.to_string()))
+        } else {
+            tokio_postgres::Config::from_str(db)
+                .map(|_| Dsn::Postgres(db.to_string()))
+                .map_err(|e| PersistorError::UnsupportedDsn(e.to_string()))
+        }
+    }
+}
+
+/// Connection settings of the postgres backend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolOptions {
+    pub tls: bool,
+    /// PEM file of an extra trusted CA
+    pub ca_file: Option<String>,
+    pub max_size: usize,
+    pub connect_timeout: Duration,
+    pub query_timeout: Duration,
+    pub health_check_interval: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            tls: false,
+            ca_file: None,
+            max_size: 4,
+            connect_timeout: Duration::from_secs(10),
+            query_timeout: Duration::from_secs(30),
+            health_check_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+pub struct Persistor {
+    store: Box<dyn CursorStore>,
+    base_block: u64,
+}
+
+impl Persistor {
+    pub async fn new(db: &str, options: &PoolOptions, base_block: u64) -> Result<Self> {
+        let store: Box<dyn CursorStore> = match Dsn::parse(db)? {
+            Dsn::Postgres(dsn) => Box::new(PostgresStore::connect(&dsn, options).await?),
+            Dsn::Sqlite(path) => Box::new(SqliteStore::open(&path).await?),
+            Dsn::Memory => {
+                HEALTH.set_db_connected(true);
+                Box::new(MemoryStore::default())
+            }
+        };
+        Ok(Self::with_store(store, base_block))
+    }
+
+    pub fn with_store(store: Box<dyn CursorStore>, base_block: u64) -> Self {
+        Self { store, base_block }
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        self.store.ping().await
+    }
+
+    pub async fn get_block_number(&self) -> Result<u64> {
+        Ok(self
+            .store
+            .load_block_number()
+            .await?
+            .unwrap_or(self.base_block))
+    }
+
+    pub async fn commit_block(&self, record: &BlockRecord) -> Result<()> {
+        self.store.commit_block(record).await
+    }
+
+    pub async fn is_processed(&self, tx_hash: &str, log_index: u64) -> Result<bool> {
+        self.store.is_processed(tx_hash, log_index).await
+    }
+
+    pub async fn mark_processed(&self, block_number: u64, event: &ProcessedEvent) -> Result<()> {
+        self.store.mark_processed(block_number, event).await
+    }
+
+    pub async fn load_tokens(&self) -> Result<Vec<TokenRecord>> {
+        self.store.load_tokens().await
+    }
+
+    pub async fn load_pending_deposits(&self) -> Result<Vec<PendingDeposit>> {
+        self.store.load_pending_deposits().await
+    }
+
+    pub async fn save_deposit(&self, record: &DepositRecord) -> Result<()> {
+        self.store.save_deposit(record).await
+    }
+
+    pub async fn load_deposits(&self, tx_hash: &str) -> Result<Vec<DepositRecord>> {
+        self.store.load_deposits(tx_hash).await
+    }
+}
+
+/// Schema migrations of a backend, applied in order of their version.
+/// Applied migrations must never change, add a new one instead.
+type Migrations = &'static [(i32, &'static str, &'static str)];
+
+fn latest_version(migrations: Migrations) -> i32 {
+    migrations.last().map_or(0, |(version, _, _)| *version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_ordered(migrations: Migrations) {
+        for (i, (version, _, _)) in migrations.iter().enumerate() {
+            assert_eq!(i as i32 + 1, *version);
+        }
+    }
+
+    #[test]
+    fn test_migrations_ordered() {
+        assert_ordered(postgres::MIGRATIONS);
+        assert_ordered(sqlite::MIGRATIONS);
+    }
+
+    #[test]
+    fn test_dsn() {
+        assert_eq!(Dsn::Memory, Dsn::parse("memory:").unwrap());
+        assert_eq!(
+            Dsn::Sqlite("/var/lib/listener.db".to_string()),
+            Dsn::parse("sqlite:///var/lib/listener.db").unwrap()
+        );
+        assert_eq!(
+            Dsn::Sqlite(":memory:".to_string()),
+            Dsn::parse("sqlite::memory:").unwrap()
+        );
+        assert!(matches!(
+            Dsn::parse("postgresql://listener@0.0.0.0:5437/eth_listener").unwrap(),
+            Dsn::Postgres(_)
+        ));
+        assert!(Dsn::parse("sqlite:").is_err());
+    }
+
+    fn record(block_number: u64, symbol: &str) -> BlockRecord {
+        let tx_hash = format!("{:#066x}", block_number);
+        BlockRecord {
+            block_number,
+            advance_cursor: true,
+            events: vec![ProcessedEvent {
+                tx_hash: tx_hash.clone(),
+                log_index: 0,
+                event: "Deposit",
+            }],
+            tokens: if symbol.is_empty() {
+                vec![]
+            } else {
+                vec![TokenRecord {
+                    token_id: 1,
+                    address: "0x46490225a85ddfd9d79256f8c5393c0428121488".to_string(),
+                    symbol: symbol.to_string(),
+                    name: symbol.to_string(),
+                    decimals: 6,
+                }]
+            },
+            pending_deposits: vec![PendingDeposit {
+                tx_hash,
+                log_index: 0,
+                block_number,
+                token_id: 2,
+                token_address: "0x9f8f72aa9304c8b593d555f12ef6589cc3a579a2".to_string(),
+                user_id: 1,
+                amount: u128::MAX,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_persistor() {
+        for db in &["memory:", "sqlite::memory:"] {
+            let persistor = Persistor::new(db, &PoolOptions::default(), 100)
+                .await
+                .unwrap();
+            assert_eq!(100, persistor.get_block_number().await.unwrap());
+            persistor.commit_block(&record(101, "")).await.unwrap();
+            persistor.commit_block(&record(102, "USDT")).await.unwrap();
+            // a replay after a partial failure
+            persistor.commit_block(&record(102, "USDC")).await.unwrap();
+            // a backfill
+            let mut backfilled = record(50, "");
+            backfilled.advance_cursor = false;
+            persistor.commit_block(&backfilled).await.unwrap();
+            assert_eq!(102, persistor.get_block_number().await.unwrap());
+            let tokens = persistor.load_tokens().await.unwrap();
+            assert_eq!(1, tokens.len());
+            assert_eq!("USDC", tokens[0].symbol);
+            let pending = persistor.load_pending_deposits().await.unwrap();
+            assert_eq!(3, pending.len());
+            assert_eq!(u128::MAX, pending[2].amount);
+
+            let event = &record(103, "").events[0];
+            assert!(persistor
+                .is_processed(&record(102, "").events[0].tx_hash, 0)
+                .await
+                .unwrap());
+            assert!(!persistor.is_processed(&event.tx_hash, 0).await.unwrap());
+            persistor.mark_processed(103, event).await.unwrap();
+            assert!(persistor.is_processed(&event.tx_hash, 0).await.unwrap());
+            assert_eq!(102, persistor.get_block_number().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deposit_records() {
+        for db in &["memory:", "sqlite::memory:"] {
+            let persistor = Persistor::new(db, &PoolOptions::default(), 0)
+                .await
+                .unwrap();
+            let mut record = DepositRecord {
+                tx_hash: format!("{:#066x}", 1),
+                log_index: 3,
+                block_number: 1,
+                user_id: None,
+                token_id: 1,
+                asset: None,
+                amount: u128::MAX,
+                delta: None,
+                state: DepositState::Seen,
+                error: None,
+            };
+            persistor.save_deposit(&record).await.unwrap();
+            record.user_id = Some(7);
+            record.asset = Some("USDT".to_string());
+            record.state = DepositState::Failed;
+            record.error = Some("exchange unavailable".to_string());
+            persistor.save_deposit(&record).await.unwrap();
+
+            let records = persistor.load_deposits(&record.tx_hash).await.unwrap();
+            assert_eq!(vec![record.clone()], records);
+
+            // a retry starting over doesn't forget what was resolved
+            let retried = DepositRecord {
+                user_id: None,
+                asset: None,
+                state: DepositState::Seen,
+                error: None,
+                ..record.clone()
+            };
+            persistor.save_deposit(&retried).await.unwrap();
+            let records = persistor.load_deposits(&record.tx_hash).await.unwrap();
+            assert_eq!(Some(7), records[0].user_id);
+            assert_eq!(DepositState::Seen, records[0].state);
+            assert!(persistor.load_deposits("0x00").await.unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use std::fs;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod, Runtime};
+use native_tls::{Certificate, TlsConnector};
+use postgres_native_tls::MakeTlsConnector;
+use tokio::task::JoinHandle;
+use tokio_postgres::{Client, NoTls};
+
+use super::{
+    latest_version, BlockRecord, CursorStore, DepositRecord, Migrations, PendingDeposit,
+    PersistorError, PoolOptions, ProcessedEvent, Result, TokenRecord,
+};
+use crate::health::HEALTH;
+
+pub(super) const MIGRATIONS: Migrations = &[
+    (
+        1,
+        "block_log",
+        include_str!("../../migrations/postgres/0001_block_log.sql"),
+    ),
+    (
+        2,
+        "block_commit",
+        include_str!("../../migrations/postgres/0002_block_commit.sql"),
+    ),
+    (
+        3,
+        "pending_deposit",
+        include_str!("../../migrations/postgres/0003_pending_deposit.sql"),
+    ),
+    (
+        4,
+        "deposit",
+        include_str!("../../migrations/postgres/0004_deposit.sql"),
+    ),
+];
+
+/// Serializes migrations of listeners sharing a database.
+const MIGRATION_LOCK: i64 = 0x6574_685f_6c69_7374;
+
+/// A pool of postgres connections, replaced transparently when they break.
+pub struct PostgresStore {
+    pool: Pool,
+    query_timeout: Duration,
+    health_check: JoinHandle<()>,
+}
+
+fn tls_connector(options: &PoolOptions) -> Result<MakeTlsConnector> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ca_file) = &options.ca_file {
+        let pem = fs::read(ca_file)
+            .map_err(|e| PersistorError::Tls(format!("failed to read {}: {}", ca_file, e)))?;
+        let ca = Certificate::from_pem(&pem).map_err(|e| PersistorError::Tls(e.to_string()))?;
+        builder.add_root_certificate(ca);
+    }
+    let connector = builder
+        .build()
+        .map_err(|e| PersistorError::Tls(e.to_string()))?;
+    Ok(MakeTlsConnector::new(connector))
+}
+
+impl PostgresStore {
+    pub async fn connect(db: &str, options: &PoolOptions) -> Result<Self> {
+        let mut pg_config = tokio_postgres::Config::from_str(db)?;
+        pg_config.connect_timeout(options.connect_timeout);
+        let manager_config = ManagerConfig {
+            // run a query on every checkout, so broken connections are replaced
+            recycling_method: RecyclingMethod::Verified,
+        };
+        let manager = if options.tls {
+            Manager::from_config(pg_config, tls_connector(options)?, manager_config)
+        } else {
+            Manager::from_config(pg_config, NoTls, manager_config)
+        };
+        let pool = Pool::builder(manager)
+            .max_size(options.max_size)
+            .runtime(Runtime::Tokio1)
+            .wait_timeout(Some(options.connect_timeout))
+            .create_timeout(Some(options.connect_timeout))
+            .recycle_timeout(Some(options.connect_timeout))
+            .build()
+            .map_err(|e| PersistorError::PoolBuild(e.to_string()))?;
+
+        let mut client = pool.get().await?;
+        HEALTH.set_db_connected(true);
+        migrate(&mut **client).await?;
+        drop(client);
+
+        let health_check = tokio::spawn(health_check(pool.clone(), options.health_check_interval));
+        Ok(Self {
+            pool,
+            query_timeout: options.query_timeout,
+            health_check,
+        })
+    }
+
+    async fn client(&self) -> Result<deadpool_postgres::Client> {
+        let client = self.pool.get().await;
+        HEALTH.set_db_connected(client.is_ok());
+        Ok(client?)
+    }
+
+    async fn timed<T, F>(&self, query: F) -> Result<T>
+    where
+        F: Future<Output = Result<T, tokio_postgres::Error>>,
+    {
+        tokio::time::timeout(self.query_timeout, query)
+            .await
+            .map_err(|_| PersistorError::Timeout(self.query_timeout))?
+            .map_err(Into::into)
+    }
+}
+
+impl Drop for PostgresStore {
+    fn drop(&mut self) {
+        self.health_check.abort();
+    }
+}
+
+/// Keep `HEALTH` up to date even while no block is saved.
+async fn health_check(pool: Pool, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let connected = match pool.get().await {
+            Ok(client) => client.simple_query("").await.is_ok(),
+            Err(e) => {
+                error!("postgres connection error: {}", e);
+                false
+            }
+        };
+        HEALTH.set_db_connected(connected);
+    }
+}
+
+#[async_trait]
+impl CursorStore for PostgresStore {
+    async fn ping(&self) -> Result<()> {
+        let client = self.client().await?;
+        self.timed(client.simple_query("")).await?;
+        Ok(())
+    }
+
+    async fn load_block_number(&self) -> Result<Option<u64>> {
+        let client = self.client().await?;
+        let row = self
+            .timed(client.query_opt(
+                "select block_number from block_log order by created_at desc limit 1",
+                &[],
+            ))
+            .await?;
+        Ok(row.map(|row| row.get::<_, i64>("block_number") as u64))
+    }
+
+    async fn commit_block(&self, record: &BlockRecord) -> Result<()> {
+        let mut client = self.client().await?;
+        self.timed(commit_block(&mut client, record)).await
+    }
+
+    async fn is_processed(&self, tx_hash: &str, log_index: u64) -> Result<bool> {
+        let client = self.client().await?;
+        let row = self
+            .timed(client.query_opt(
+                "select 1 from processed_event where tx_hash = $1 and log_index = $2",
+                &[&tx_hash, &(log_index as i64)],
+            ))
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn mark_processed(&self, block_number: u64, event: &ProcessedEvent) -> Result<()> {
+        let client = self.client().await?;
+        self.timed(client.execute(
+            "insert into processed_event (tx_hash, log_index, block_number, event)
+            values ($1, $2, $3, $4)
+            on conflict (tx_hash, log_index) do nothing",
+            &[
+                &event.tx_hash,
+                &(event.log_index as i64),
+                &(block_number as i64),
+                &event.event,
+            ],
+        ))
+        .await?;
+        Ok(())
+    }
+
+    async fn load_tokens(&self) -> Result<Vec<TokenRecord>> {
+        let client = self.client().await?;
+        let rows = self
+            .timed(client.query(
+                "select token_id, address, symbol, name, decimals from token_cache
+                order by token_id",
+                &[],
+            ))
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| TokenRecord {
+                token_id: row.get::<_, i32>("token_id") as u16,
+                address: row.get("address"),
+                symbol: row.get("symbol"),
+                name: row.get("name"),
+                decimals: row.get::<_, i16>("decimals") as u8,
+            })
+            .collect())
+    }
+
+    async fn load_pending_deposits(&self) -> Result<Vec<PendingDeposit>> {
+        let client = self.client().await?;
+        let rows = self
+            .timed(client.query(
+                "select tx_hash, log_index, block_number, token_id, token_address, user_id, amount
+                from pending_deposit order by block_number, log_index",
+                &[],
+            ))
+            .await?;
+        rows.into_iter()
+            .map(|row| {
+                let amount: String = row.get("amount");
+                Ok(PendingDeposit {
+                    tx_hash: row.get("tx_hash"),
+                    log_index: row.get::<_, i64>("log_index") as u64,
+                    block_number: row.get::<_, i64>("block_number") as u64,
+                    token_id: row.get::<_, i32>("token_id") as u16,
+                    token_address: row.get("token_address"),
+                    user_id: row.get::<_, i32>("user_id") as u16,
+                    amount: amount
+                        .parse()
+                        .map_err(|_| PersistorError::InvalidAmount(amount.clone()))?,
+                })
+            })
+            .collect()
+    }
+
+    async fn save_deposit(&self, record: &DepositRecord) -> Result<()> {
+        let client = self.client().await?;
+        self.timed(client.execute(
+            "insert into deposit (tx_hash, log_index, block_number, user_id, token_id, asset,
+                amount, delta, state, error)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            on conflict (tx_hash, log_index) do update set
+                user_id = coalesce(excluded.user_id, deposit.user_id),
+                asset = coalesce(excluded.asset, deposit.asset),
+                delta = coalesce(excluded.delta, deposit.delta),
+                state = excluded.state,
+                error = excluded.error,
+                updated_at = current_timestamp",
+            &[
+                &record.tx_hash,
+                &(record.log_index as i64),
+                &(record.block_number as i64),
+                &record.user_id.map(i32::from),
+                &(record.token_id as i32),
+                &record.asset,
+                &record.amount.to_string(),
+                &record.delta,
+                &record.state.as_str(),
+                &record.error,
+            ],
+        ))
+        .await?;
+        Ok(())
+    }
+
+    async fn load_deposits(&self, tx_hash: &str) -> Result<Vec<DepositRecord>> {
+        let client = self.client().await?;
+        let rows = self
+            .timed(client.query(
+                "select tx_hash, log_index, block_number, user_id, token_id, asset, amount, delta,
+                    state, error
+                from deposit where tx_hash = $1 order by log_index",
+                &[&tx_hash],
+            ))
+            .await?;
+        rows.into_iter()
+            .map(|row| {
+                let amount: String = row.get("amount");
+                let state: String = row.get("state");
+                Ok(DepositRecord {
+                    tx_hash: row.get("tx_hash"),
+                    log_index: row.get::<_, i64>("log_index") as u64,
+                    block_number: row.get::<_, i64>("block_number") as u64,
+                    user_id: row.get::<_, Option<i32>>("user_id").map(|id| id as u16),
+                    token_id: row.get::<_, i32>("token_id") as u16,
+                    asset: row.get("asset"),
+                    amount: amount
+                        .parse()
+                        .map_err(|_| PersistorError::InvalidAmount(amount.clone()))?,
+                    delta: row.get("delta"),
+                    state: state.parse()?,
+                    error: row.get("error"),
+                })
+            })
+            .collect()
+    }
+}
+
+async fn commit_block(
+    client: &mut Client,
+    record: &BlockRecord,
+) -> Result<(), tokio_postgres::Error> {
+    let tx = client.transaction().await?;
+    let block_number = record.block_number as i64;
+    if record.advance_cursor {
+        tx.execute(
+            "insert into block_log (block_number) values ($1)
+            on conflict (block_number) do nothing",
+            &[&block_number],
+        )
+        .await?;
+    }
+    for event in &record.events {
+        tx.execute(
+            "insert into processed_event (tx_hash, log_index, block_number, event)
+            values ($1, $2, $3, $4)
+            on conflict (tx_hash, log_index) do nothing",
+            &[
+                &event.tx_hash,
+                &(event.log_index as i64),
+                &block_number,
+                &event.event,
+            ],
+        )
+        .await?;
+    }
+    for token in &record.tokens {
+        tx.execute(
+            "insert into token_cache (token_id, address, symbol, name, decimals)
+            values ($1, $2, $3, $4, $5)
+            on conflict (token_id) do update set
+                address = excluded.address,
+                symbol = excluded.symbol,
+                name = excluded.name,
+                decimals = excluded.decimals,
+                updated_at = current_timestamp",
+            &[
+                &(token.token_id as i32),
+                &token.address,
+                &token.symbol,
+                &token.name,
+                &(token.decimals as i16),
+            ],
+        )
+        .await?;
+    }
+    for deposit in &record.pending_deposits {
+        tx.execute(
+            "insert into pending_deposit
+                (tx_hash, log_index, block_number, token_id, token_address, user_id, amount)
+            values ($1, $2, $3, $4, $5, $6, $7)
+            on conflict (tx_hash, log_index) do nothing",
+            &[
+                &deposit.tx_hash,
+                &(deposit.log_index as i64),
+                &(deposit.block_number as i64),
+                &(deposit.token_id as i32),
+                &deposit.token_address,
+                &(deposit.user_id as i32),
+                &deposit.amount.to_string(),
+            ],
+        )
+        .await?;
+    }
+    tx.commit().await
+}
+
+/// Bring the schema up to the latest version, each migration in its own transaction.
+async fn migrate(client: &mut Client) -> Result<()> {
+    // under the lock too, concurrent `create table if not exists` can collide in the catalog
+    let tx = client.transaction().await?;
+    tx.execute("select pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+        .await?;
+    tx.batch_execute(
+        "create table if not exists schema_version (
+            version integer primary key,
+            name text not null,
+            applied_at timestamp not null default current_timestamp
+        )",
+    )
+    .await?;
+    tx.commit().await?;
+    let latest = latest_version(MIGRATIONS);
+    for (version, name, sql) in MIGRATIONS {
+        let tx = client.transaction().await?;
+        tx.execute("select pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+            .await?;
+        let current: i32 = tx
+            .query_one("select coalesce(max(version), 0) from schema_version", &[])
+            .await?
+            .get(0);
+        if current > latest {
+            return Err(PersistorError::SchemaTooNew(current, latest));
+        }
+        if *version <= current {
+            continue;
+        }
+        info!("applying migration {} {}", version, name);
+        tx.batch_execute(sql).await?;
+        tx.execute(
+            "insert into schema_version (version, name) values ($1, $2)",
+            &[version, name],
+        )
+        .await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
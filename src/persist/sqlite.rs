@@ -0,0 +1,367 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+
+use super::{
+    latest_version, BlockRecord, CursorStore, DepositRecord, DepositState, Migrations,
+    PendingDeposit, PersistorError, ProcessedEvent, Result, TokenRecord,
+};
+use crate::health::HEALTH;
+
+pub(super) const MIGRATIONS: Migrations = &[
+    (
+        1,
+        "block_log",
+        include_str!("../../migrations/sqlite/0001_block_log.sql"),
+    ),
+    (
+        2,
+        "block_commit",
+        include_str!("../../migrations/sqlite/0002_block_commit.sql"),
+    ),
+    (
+        3,
+        "pending_deposit",
+        include_str!("../../migrations/sqlite/0003_pending_deposit.sql"),
+    ),
+    (
+        4,
+        "deposit",
+        include_str!("../../migrations/sqlite/0004_deposit.sql"),
+    ),
+];
+
+/// A SQLite database file, accessed on the blocking thread pool.
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub async fn open(path: &str) -> Result<Self> {
+        let path = path.to_string();
+        let conn = Self::blocking(move || {
+            let mut conn = Connection::open(path)?;
+            migrate(&mut conn)?;
+            Ok(conn)
+        })
+        .await?;
+        HEALTH.set_db_connected(true);
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    async fn blocking<T, F>(f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        tokio::task::spawn_blocking(f)
+            .await
+            .expect("sqlite task panicked")
+    }
+}
+
+#[async_trait]
+impl CursorStore for SqliteStore {
+    async fn ping(&self) -> Result<()> {
+        let conn = self.conn.clone();
+        Self::blocking(move || {
+            conn.lock().unwrap().query_row("select 1", [], |_| Ok(()))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn load_block_number(&self) -> Result<Option<u64>> {
+        let conn = self.conn.clone();
+        Self::blocking(move || {
+            let block_number = conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    "select block_number from block_log order by id desc limit 1",
+                    [],
+                    |row| row.get::<_, i64>(0),
+                )
+                .optional()?;
+            Ok(block_number.map(|n| n as u64))
+        })
+        .await
+    }
+
+    async fn commit_block(&self, record: &BlockRecord) -> Result<()> {
+        let conn = self.conn.clone();
+        let record = record.clone();
+        Self::blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            if record.advance_cursor {
+                tx.execute(
+                    "insert into block_log (block_number) values (?1)
+                    on conflict (block_number) do nothing",
+                    params![record.block_number as i64],
+                )?;
+            }
+            for event in &record.events {
+                tx.execute(
+                    "insert into processed_event (tx_hash, log_index, block_number, event)
+                    values (?1, ?2, ?3, ?4)
+                    on conflict (tx_hash, log_index) do nothing",
+                    params![
+                        event.tx_hash,
+                        event.log_index as i64,
+                        record.block_number as i64,
+                        event.event
+                    ],
+                )?;
+            }
+            for token in &record.tokens {
+                tx.execute(
+                    "insert into token_cache (token_id, address, symbol, name, decimals)
+                    values (?1, ?2, ?3, ?4, ?5)
+                    on conflict (token_id) do update set
+                        address = excluded.address,
+                        symbol = excluded.symbol,
+                        name = excluded.name,
+                        decimals = excluded.decimals,
+                        updated_at = current_timestamp",
+                    params![
+                        token.token_id,
+                        token.address,
+                        token.symbol,
+                        token.name,
+                        token.decimals
+                    ],
+                )?;
+            }
+            for deposit in &record.pending_deposits {
+                tx.execute(
+                    "insert into pending_deposit
+                        (tx_hash, log_index, block_number, token_id, token_address, user_id, amount)
+                    values (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                    on conflict (tx_hash, log_index) do nothing",
+                    params![
+                        deposit.tx_hash,
+                        deposit.log_index as i64,
+                        deposit.block_number as i64,
+                        deposit.token_id,
+                        deposit.token_address,
+                        deposit.user_id,
+                        deposit.amount.to_string()
+                    ],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn is_processed(&self, tx_hash: &str, log_index: u64) -> Result<bool> {
+        let conn = self.conn.clone();
+        let tx_hash = tx_hash.to_string();
+        Self::blocking(move || {
+            let processed = conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    "select 1 from processed_event where tx_hash = ?1 and log_index = ?2",
+                    params![tx_hash, log_index as i64],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(processed.is_some())
+        })
+        .await
+    }
+
+    async fn mark_processed(&self, block_number: u64, event: &ProcessedEvent) -> Result<()> {
+        let conn = self.conn.clone();
+        let event = event.clone();
+        Self::blocking(move || {
+            conn.lock().unwrap().execute(
+                "insert into processed_event (tx_hash, log_index, block_number, event)
+                values (?1, ?2, ?3, ?4)
+                on conflict (tx_hash, log_index) do nothing",
+                params![
+                    event.tx_hash,
+                    event.log_index as i64,
+                    block_number as i64,
+                    event.event
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn load_tokens(&self) -> Result<Vec<TokenRecord>> {
+        let conn = self.conn.clone();
+        Self::blocking(move || {
+            let conn = conn.lock().unwrap();
+            let mut stmt = conn.prepare(
+                "select token_id, address, symbol, name, decimals from token_cache
+                order by token_id",
+            )?;
+            let tokens = stmt
+                .query_map([], |row| {
+                    Ok(TokenRecord {
+                        token_id: row.get(0)?,
+                        address: row.get(1)?,
+                        symbol: row.get(2)?,
+                        name: row.get(3)?,
+                        decimals: row.get(4)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(tokens)
+        })
+        .await
+    }
+
+    async fn load_pending_deposits(&self) -> Result<Vec<PendingDeposit>> {
+        let conn = self.conn.clone();
+        Self::blocking(move || {
+            let conn = conn.lock().unwrap();
+            let mut stmt = conn.prepare(
+                "select tx_hash, log_index, block_number, token_id, token_address, user_id, amount
+                from pending_deposit order by block_number, log_index",
+            )?;
+            let deposits = stmt
+                .query_map([], |row| {
+                    Ok(PendingDeposit {
+                        tx_hash: row.get(0)?,
+                        log_index: row.get::<_, i64>(1)? as u64,
+                        block_number: row.get::<_, i64>(2)? as u64,
+                        token_id: row.get(3)?,
+                        token_address: row.get(4)?,
+                        user_id: row.get(5)?,
+                        amount: amount_column(row, 6)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(deposits)
+        })
+        .await
+    }
+
+    async fn save_deposit(&self, record: &DepositRecord) -> Result<()> {
+        let conn = self.conn.clone();
+        let record = record.clone();
+        Self::blocking(move || {
+            conn.lock().unwrap().execute(
+                "insert into deposit (tx_hash, log_index, block_number, user_id, token_id, asset,
+                    amount, delta, state, error)
+                values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                on conflict (tx_hash, log_index) do update set
+                    user_id = coalesce(excluded.user_id, deposit.user_id),
+                    asset = coalesce(excluded.asset, deposit.asset),
+                    delta = coalesce(excluded.delta, deposit.delta),
+                    state = excluded.state,
+                    error = excluded.error,
+                    updated_at = current_timestamp",
+                params![
+                    record.tx_hash,
+                    record.log_index as i64,
+                    record.block_number as i64,
+                    record.user_id,
+                    record.token_id,
+                    record.asset,
+                    record.amount.to_string(),
+                    record.delta,
+                    record.state.as_str(),
+                    record.error
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn load_deposits(&self, tx_hash: &str) -> Result<Vec<DepositRecord>> {
+        let conn = self.conn.clone();
+        let tx_hash = tx_hash.to_string();
+        Self::blocking(move || {
+            let conn = conn.lock().unwrap();
+            let mut stmt = conn.prepare(
+                "select tx_hash, log_index, block_number, user_id, token_id, asset, amount, delta,
+                    state, error
+                from deposit where tx_hash = ?1 order by log_index",
+            )?;
+            let deposits = stmt
+                .query_map([&tx_hash], |row| {
+                    Ok(DepositRecord {
+                        tx_hash: row.get(0)?,
+                        log_index: row.get::<_, i64>(1)? as u64,
+                        block_number: row.get::<_, i64>(2)? as u64,
+                        user_id: row.get(3)?,
+                        token_id: row.get(4)?,
+                        asset: row.get(5)?,
+                        amount: amount_column(row, 6)?,
+                        delta: row.get(7)?,
+                        state: state_column(row, 8)?,
+                        error: row.get(9)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(deposits)
+        })
+        .await
+    }
+}
+
+/// Amounts are stored as text, they don't fit in an integer column.
+fn amount_column(row: &Row, idx: usize) -> rusqlite::Result<u128> {
+    let raw: String = row.get(idx)?;
+    raw.parse()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+}
+
+fn state_column(row: &Row, idx: usize) -> rusqlite::Result<DepositState> {
+    let raw: String = row.get(idx)?;
+    raw.parse().map_err(|_| {
+        rusqlite::Error::FromSqlConversionFailure(
+            idx,
+            Type::Text,
+            format!("invalid deposit state {:?}", raw).into(),
+        )
+    })
+}
+
+/// Bring the schema up to the latest version, each migration in its own transaction.
+fn migrate(conn: &mut Connection) -> Result<()> {
+    conn.execute_batch(
+        "create table if not exists schema_version (
+            version integer primary key,
+            name text not null,
+            applied_at timestamp not null default current_timestamp
+        )",
+    )?;
+    let latest = latest_version(MIGRATIONS);
+    for (version, name, sql) in MIGRATIONS {
+        // immediate, so concurrent listeners wait for each other
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let current: i32 = tx.query_row(
+            "select coalesce(max(version), 0) from schema_version",
+            [],
+            |row| row.get(0),
+        )?;
+        if current > latest {
+            return Err(PersistorError::SchemaTooNew(current, latest));
+        }
+        if *version <= current {
+            continue;
+        }
+        info!("applying migration {} {}", version, name);
+        tx.execute_batch(sql)?;
+        tx.execute(
+            "insert into schema_version (version, name) values (?1, ?2)",
+            params![version, name],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,569 @@
+use std::convert::TryFrom;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use ethers::prelude::*;
+use tonic::transport::Channel;
+
+use crate::amount;
+use crate::events::*;
+use crate::exchange::matchengine_client::MatchengineClient;
+use crate::exchange::{BalanceUpdateRequest, EthLogMetadata, UserInfo};
+use crate::health::HEALTH;
+use crate::infos::{ContractInfoError, ContractInfos};
+use crate::persist::{
+    DepositRecord, DepositState, PendingDeposit, Persistor, ProcessedEvent, TokenRecord,
+};
+use crate::provider::HttpProvider;
+#[cfg(feature = "new_token")]
+use crate::restapi::{NewAssetReq, NewMarketReq, RestClient};
+use crate::{config, metrics, CONFIG};
+
+/// A helper to convert ethers Log to EthLogMetadata
+trait ToLogMeta {
+    fn to_log_meta(&self) -> EthLogMetadata;
+}
+
+impl ToLogMeta for Log {
+    fn to_log_meta(&self) -> EthLogMetadata {
+        EthLogMetadata {
+            block_number: self.block_number.unwrap().as_u64(),
+            tx_hash: format!("{:#x}", self.transaction_hash.unwrap()),
+            log_index: format!("{:#x}", self.log_index.unwrap()),
+        }
+    }
+}
+
+/// The id the exchange dedupes a balance update by, the same for every dispatch of the
+/// log: its block number in the high and its index in the block in the low 32 bits.
+fn business_id(log: &Log) -> u64 {
+    let block_number = log.block_number.unwrap().as_u64();
+    let log_index = log.log_index.unwrap().low_u64();
+    (block_number << 32) | (log_index & u64::from(u32::MAX))
+}
+
+/// Record latency and failures of a gRPC call to the exchange.
+async fn observe_grpc<T, F>(method: &str, call: F) -> Result<T, tonic::Status>
+where
+    F: Future<Output = Result<T, tonic::Status>>,
+{
+    let timer = metrics::GRPC_LATENCY
+        .with_label_values(&[method])
+        .start_timer();
+    let result = call.await;
+    timer.observe_duration();
+    match &result {
+        Ok(_) => HEALTH.set_grpc_connected(true),
+        Err(status) => {
+            metrics::GRPC_ERRORS.with_label_values(&[method]).inc();
+            HEALTH.set_grpc_connected(status.code() != tonic::Code::Unavailable);
+        }
+    }
+    result
+}
+
+/// Fetch and decode contract events in the block range `[from, to]`.
+pub async fn fetch_events(
+    provider: &HttpProvider,
+    contract_address: Address,
+    from: u64,
+    to: u64,
+) -> Result<Vec<Events>> {
+    let log_filter = Filter::default()
+        .from_block(from)
+        .to_block(to)
+        .address(ValueOrArray::Value(contract_address));
+    let logs = match CONFIG.web3().quorum() {
+        Some(quorum) => quorum_logs(provider, &log_filter, quorum).await?,
+        None => {
+            metrics::rpc_call("eth_getLogs");
+            provider.get_logs(&log_filter).await?
+        }
+    };
+    let events = logs
+        .into_iter()
+        .filter_map(|log| Events::try_from(log).ok())
+        .collect();
+    Ok(events)
+}
+
+/// Delay before asking the endpoints again when they don't reach a quorum, doubled on
+/// every attempt up to `MAX_QUORUM_DELAY`.
+const QUORUM_DELAY: Duration = Duration::from_secs(1);
+const MAX_QUORUM_DELAY: Duration = Duration::from_secs(30);
+/// Attempts before giving up on a quorum, about three minutes with the delays above.
+const QUORUM_ATTEMPTS: u32 = 10;
+
+/// What endpoints must agree on, other fields like `removed` vary between node clients.
+type LogKey = (
+    Option<H256>,
+    Option<H256>,
+    Option<U256>,
+    Address,
+    Vec<H256>,
+    Bytes,
+);
+
+fn log_key(log: &Log) -> LogKey {
+    (
+        log.block_hash,
+        log.transaction_hash,
+        log.log_index,
+        log.address,
+        log.topics.clone(),
+        log.data.clone(),
+    )
+}
+
+/// Fetch logs from every endpoint and accept them once `quorum` endpoints agree, asking
+/// again up to `QUORUM_ATTEMPTS` times.
+async fn quorum_logs(provider: &HttpProvider, filter: &Filter, quorum: usize) -> Result<Vec<Log>> {
+    let client = provider.as_ref();
+    let mut delay = QUORUM_DELAY;
+    for attempt in 1..=QUORUM_ATTEMPTS {
+        let mut answers: Vec<(Vec<LogKey>, Vec<Log>, usize)> = Vec::new();
+        for result in client
+            .request_all::<_, Vec<Log>>("eth_getLogs", [filter])
+            .await
+        {
+            match result {
+                Ok(logs) => {
+                    let keys: Vec<LogKey> = logs.iter().map(log_key).collect();
+                    match answers.iter_mut().find(|(answer, _, _)| *answer == keys) {
+                        Some((_, _, votes)) => *votes += 1,
+                        None => answers.push((keys, logs, 1)),
+                    }
+                }
+                Err(e) => warn!("eth_getLogs failed on one endpoint: {}", e),
+            }
+        }
+        if answers.len() > 1 {
+            warn!("endpoints disagree on eth_getLogs for {:?}", filter);
+        }
+        if let Some((_, logs, _)) = answers.into_iter().find(|(_, _, votes)| *votes >= quorum) {
+            return Ok(logs);
+        }
+        if attempt == QUORUM_ATTEMPTS {
+            break;
+        }
+        warn!(
+            "less than {} endpoints agree on eth_getLogs, asking again in {:?}",
+            quorum, delay
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_QUORUM_DELAY);
+    }
+    anyhow::bail!(
+        "less than {} endpoints agree on eth_getLogs after {} attempts",
+        quorum,
+        QUORUM_ATTEMPTS
+    )
+}
+
+/// Fetch and decode contract events emitted by transaction `tx_hash`.
+pub async fn fetch_tx_events(
+    provider: &HttpProvider,
+    contract_address: Address,
+    tx_hash: H256,
+) -> Result<Vec<Events>> {
+    metrics::rpc_call("eth_getTransactionReceipt");
+    let receipt = provider
+        .get_transaction_receipt(tx_hash)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("transaction {:#x} not found", tx_hash))?;
+    let events = receipt
+        .logs
+        .into_iter()
+        .filter(|log| log.address == contract_address)
+        .filter_map(|log| Events::try_from(log).ok())
+        .collect();
+    Ok(events)
+}
+
+/// Confirmations required by the deposits in `events` on top of the block finality.
+pub fn required_confirmations(events: &[Events]) -> u64 {
+    let live = config::current();
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Events::Deposit(deposit) => Some(
+                live.web3()
+                    .deposit_confirmations(deposit.token_id, deposit.amount),
+            ),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Wait until block `block_number` is `confirmations` blocks behind the chain head.
+pub async fn wait_confirmations(
+    provider: &HttpProvider,
+    block_number: u64,
+    confirmations: u64,
+) -> Result<()> {
+    loop {
+        metrics::rpc_call("eth_blockNumber");
+        let head = provider.get_block_number().await?.as_u64();
+        if head >= block_number + confirmations {
+            return Ok(());
+        }
+        debug!(
+            "block#{} waits for {} confirmations (latest block at #{})",
+            block_number, confirmations, head
+        );
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Dispatches decoded contract events to the exchange.
+pub struct Processor {
+    provider: Arc<HttpProvider>,
+    contract_address: Address,
+    grpc_client: MatchengineClient<Channel>,
+    contract_infos: ContractInfos<HttpProvider>,
+    #[cfg(feature = "new_token")]
+    rest_client: RestClient,
+    /// deposits of unlisted tokens since the last `take_pending_deposits`
+    pending_deposits: Vec<PendingDeposit>,
+    /// where dispatched events and every step of a deposit are saved, if set
+    persistor: Option<Arc<Persistor>>,
+    /// dispatch events `persistor` knows as dispatched again
+    force: bool,
+}
+
+impl Processor {
+    pub fn new(
+        provider: Arc<HttpProvider>,
+        contract_address: Address,
+        grpc_client: MatchengineClient<Channel>,
+        contract_infos: ContractInfos<HttpProvider>,
+        #[cfg(feature = "new_token")] rest_client: RestClient,
+    ) -> Self {
+        Self {
+            provider,
+            contract_address,
+            grpc_client,
+            contract_infos,
+            #[cfg(feature = "new_token")]
+            rest_client,
+            pending_deposits: Vec::new(),
+            persistor: None,
+            force: false,
+        }
+    }
+
+    /// Skip the events `persistor` knows as dispatched, and save the progress of every
+    /// deposit to it.
+    pub fn set_persistor(&mut self, persistor: Arc<Persistor>) {
+        self.persistor = Some(persistor);
+    }
+
+    /// Dispatch events again even if `persistor` knows them as dispatched, the exchange
+    /// dedupes the balance updates by their business_id.
+    pub fn set_force(&mut self, force: bool) {
+        self.force = force;
+    }
+
+    pub fn contract_infos_mut(&mut self) -> &mut ContractInfos<HttpProvider> {
+        &mut self.contract_infos
+    }
+
+    pub fn seed_tokens(&mut self, tokens: Vec<TokenRecord>) {
+        self.contract_infos.seed_tokens(tokens);
+    }
+
+    /// Tokens learned while processing, to be persisted with the block.
+    pub fn take_token_updates(&mut self) -> Vec<TokenRecord> {
+        self.contract_infos.take_token_updates()
+    }
+
+    /// Deposits held while processing, to be persisted with the block.
+    pub fn take_pending_deposits(&mut self) -> Vec<PendingDeposit> {
+        std::mem::take(&mut self.pending_deposits)
+    }
+
+    /// Keep a deposit which can't be credited, it is persisted with the block.
+    fn hold_deposit(&mut self, deposit: &Deposit, address: Address, user_id: u16) {
+        metrics::DEPOSITS_HELD.inc();
+        let origin = &deposit.origin;
+        self.pending_deposits.push(PendingDeposit {
+            tx_hash: format!("{:#x}", origin.transaction_hash.unwrap_or_default()),
+            log_index: origin.log_index.unwrap_or_default().as_u64(),
+            block_number: origin.block_number.unwrap_or_default().as_u64(),
+            token_id: deposit.token_id,
+            token_address: format!("{:#x}", address),
+            user_id,
+            amount: deposit.amount,
+        });
+    }
+
+    /// Save how far a deposit got, a failure is only logged as it must not block crediting.
+    async fn journal(&self, record: &DepositRecord) {
+        if let Some(persistor) = &self.persistor {
+            if let Err(e) = persistor.save_deposit(record).await {
+                warn!(
+                    "failed to save deposit {}:{} as {}: {}",
+                    record.tx_hash,
+                    record.log_index,
+                    record.state.as_str(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// The saved record of the deposit `record` is about, if any.
+    async fn recorded(&self, record: &DepositRecord) -> Result<Option<DepositRecord>> {
+        let persistor = match &self.persistor {
+            Some(persistor) => persistor,
+            None => return Ok(None),
+        };
+        let known = persistor
+            .load_deposits(&record.tx_hash)
+            .await?
+            .into_iter()
+            .find(|known| known.log_index == record.log_index);
+        Ok(known)
+    }
+
+    /// Whether `event` was dispatched already and is to be skipped, never when forced.
+    pub async fn is_processed(&self, event: &ProcessedEvent) -> Result<bool> {
+        match &self.persistor {
+            Some(persistor) if !self.force => Ok(persistor
+                .is_processed(&event.tx_hash, event.log_index)
+                .await?),
+            _ => Ok(false),
+        }
+    }
+
+    async fn advance(&self, record: &mut DepositRecord, state: DepositState) {
+        record.state = state;
+        self.journal(record).await;
+    }
+
+    async fn deposit(&mut self, deposit: &Deposit, record: &mut DepositRecord) -> Result<()> {
+        let user_id = self.contract_infos.fetch_user_id(&deposit.to).await?;
+        record.user_id = Some(user_id);
+        self.advance(record, DepositState::UserResolved).await;
+
+        // only the address is resolved yet, an unlisted token's contract is never queried
+        let address = self
+            .contract_infos
+            .fetch_token_address(deposit.token_id)
+            .await?;
+        if !self.contract_infos.is_listed(address) {
+            warn!(
+                "holding deposit of unlisted token {:#x}: {:?}",
+                address, deposit
+            );
+            self.hold_deposit(deposit, address, user_id);
+            record.error = Some(format!("unlisted token {:#x}", address));
+            self.advance(record, DepositState::Held).await;
+            return Ok(());
+        }
+        let erc20 = self.contract_infos.fetch_erc20(address).await?;
+        record.asset = Some(erc20.symbol.clone());
+        self.advance(record, DepositState::TokenResolved).await;
+
+        let precision = if config::current().tokens().truncate_to_prec_save() {
+            let asset = self.contract_infos.fetch_assets(deposit.token_id).await?;
+            Some(asset.prec_save)
+        } else {
+            None
+        };
+        let asset = erc20.symbol;
+        let amount = match amount::to_decimal(deposit.amount, erc20.decimals as u32, precision) {
+            Ok(amount) => amount,
+            Err(e) => {
+                warn!("holding deposit of {}, {}: {:?}", asset, e, deposit);
+                self.hold_deposit(deposit, address, user_id);
+                record.error = Some(e.to_string());
+                self.advance(record, DepositState::Held).await;
+                return Ok(());
+            }
+        };
+        if amount.dust > 0 {
+            warn!(
+                "deposit of {} {} credited as {}, dropping {} below the kept precision",
+                deposit.amount, asset, amount.value, amount.dust
+            );
+            metrics::DEPOSITS_TRUNCATED
+                .with_label_values(&[asset.as_str()])
+                .inc();
+        }
+        record.delta = Some(amount.value.to_string());
+        self.advance(record, DepositState::Sent).await;
+        observe_grpc(
+            "balance_update",
+            self.grpc_client.balance_update(BalanceUpdateRequest {
+                user_id: user_id as u32,
+                asset,
+                business: "deposit".to_string(),
+                business_id: business_id(&deposit.origin),
+                delta: format!("{}", amount.value),
+                detail: "".to_string(),
+                signature: Some("".to_string()),
+                log_metadata: Some(deposit.origin.to_log_meta()),
+            }),
+        )
+        .await?;
+        self.advance(record, DepositState::Acknowledged).await;
+        Ok(())
+    }
+
+    pub async fn fetch_block(&self, block_number: u64) -> Result<Vec<Events>> {
+        fetch_events(
+            &self.provider,
+            self.contract_address,
+            block_number,
+            block_number,
+        )
+        .await
+    }
+
+    pub async fn fetch_tx(&self, tx_hash: H256) -> Result<Vec<Events>> {
+        fetch_tx_events(&self.provider, self.contract_address, tx_hash).await
+    }
+
+    pub async fn process_events(&mut self, events: Vec<Events>) -> Result<()> {
+        for event in events {
+            let name = event.name();
+            let block_number = event.origin().block_number.unwrap_or_default().as_u64();
+            let processed = ProcessedEvent::from(&event);
+            // a restart, backfill or replay must not apply an event twice
+            if self.is_processed(&processed).await? {
+                info!(
+                    "skipping {} event {}:{}, already processed",
+                    name, processed.tx_hash, processed.log_index
+                );
+                continue;
+            }
+            let held = self.pending_deposits.len();
+            match self.dispatch(event).await {
+                // Applied on the exchange, recorded right away so a failure later in the block
+                // doesn't apply it again. Held deposits are recorded with the block instead,
+                // together with their hold.
+                Ok(()) if self.pending_deposits.len() == held => {
+                    if let Some(persistor) = &self.persistor {
+                        persistor.mark_processed(block_number, &processed).await?;
+                    }
+                }
+                Ok(()) => {}
+                Err(e) => match e.downcast_ref::<ContractInfoError>() {
+                    Some(ContractInfoError::Quarantined(..)) => {
+                        warn!("skipping {} event: {}", name, e);
+                        metrics::EVENTS_QUARANTINED.with_label_values(&[name]).inc();
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn dispatch(&mut self, event: Events) -> Result<()> {
+        info!("process event: {:?}", event);
+        metrics::EVENTS_PROCESSED
+            .with_label_values(&[event.name()])
+            .inc();
+        match event {
+            Events::Deposit(deposit) => {
+                let mut record = DepositRecord::from(&deposit);
+                match self.recorded(&record).await? {
+                    Some(known) if known.state == DepositState::Acknowledged && !self.force => {
+                        info!(
+                            "deposit {}:{} is credited already, skipping",
+                            record.tx_hash, record.log_index
+                        );
+                        return Ok(());
+                    }
+                    // what an earlier attempt resolved is kept until resolved again
+                    Some(known) => {
+                        record.user_id = known.user_id;
+                        record.asset = known.asset;
+                        record.delta = known.delta;
+                    }
+                    None => {}
+                }
+                self.journal(&record).await;
+                if let Err(e) = self.deposit(&deposit, &mut record).await {
+                    // held like an unlisted token's deposit, it would be lost if only skipped
+                    if let (Some(ContractInfoError::Quarantined(address, _)), Some(user_id)) =
+                        (e.downcast_ref::<ContractInfoError>(), record.user_id)
+                    {
+                        warn!("holding deposit {:?}: {}", deposit, e);
+                        metrics::EVENTS_QUARANTINED
+                            .with_label_values(&["Deposit"])
+                            .inc();
+                        self.hold_deposit(&deposit, *address, user_id);
+                        record.error = Some(e.to_string());
+                        self.advance(&mut record, DepositState::Held).await;
+                        return Ok(());
+                    }
+                    record.state = DepositState::Failed;
+                    record.error = Some(e.to_string());
+                    self.journal(&record).await;
+                    return Err(e);
+                }
+            }
+            #[cfg(feature = "new_token")]
+            Events::NewToken(new_token) => {
+                // checked first, the contract of an unlisted token is never queried
+                if !self.contract_infos.is_listed(new_token.token_addr) {
+                    info!(
+                        "not registering unlisted token {:#x} on the exchange",
+                        new_token.token_addr
+                    );
+                    return Ok(());
+                }
+                let asset = self
+                    .contract_infos
+                    .add_token(new_token.token_addr, new_token.token_id)
+                    .await?;
+                let markets = config::current().markets().for_asset(&asset.id);
+                self.rest_client
+                    .add_assets(&NewAssetReq {
+                        assets: vec![asset],
+                        not_reload: false,
+                    })
+                    .await?;
+                for market in markets.unwrap_or_default() {
+                    let name = market.name.clone();
+                    let created = self
+                        .rest_client
+                        .add_market(&NewMarketReq {
+                            market,
+                            asset_base: None,
+                            asset_quote: None,
+                            not_reload: false,
+                        })
+                        .await;
+                    // the asset is registered, a market can still be created by hand
+                    match created {
+                        Ok(()) => info!("created market {}", name),
+                        Err(e) => error!("failed to create market {}: {}", name, e),
+                    }
+                }
+            }
+            Events::RegisterUser(register_user) => {
+                observe_grpc(
+                    "register_user",
+                    self.grpc_client.register_user(UserInfo {
+                        user_id: register_user.user_id as u32,
+                        l1_address: register_user.eth_addr.to_string(),
+                        l2_pubkey: hex::encode(register_user.bjj_pubkey),
+                        log_metadata: Some(register_user.origin.to_log_meta()),
+                    }),
+                )
+                .await?;
+            }
+            _ => {
+                warn!("ignoring {:?}", event);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use std::cmp::Reverse;
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use async_trait::async_trait;
+use ethers::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::metrics;
+use crate::rate_limit::{self, RateLimited, Throttled};
+
+pub type HttpProvider = Provider<FailoverClient>;
+
+const MAX_SCORE: i64 = 100;
+const MIN_SCORE: i64 = -MAX_SCORE;
+const FAILURE_PENALTY: i64 = 10;
+
+#[derive(Debug)]
+struct Endpoint {
+    /// only the host, the url may embed an api key
+    host: String,
+    /// paced by the budget of its host, retrying throttled requests on the same endpoint
+    client: RateLimited<KeylessHttp>,
+    /// raised on success and lowered on transport failures, the best one is tried first
+    score: AtomicI64,
+}
+
+impl Endpoint {
+    fn adjust_score(&self, delta: i64) {
+        let _ = self
+            .score
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |score| {
+                Some((score + delta).max(MIN_SCORE).min(MAX_SCORE))
+            });
+    }
+}
+
+/// An http client whose errors don't carry its url, which may embed an api key.
+#[derive(Debug)]
+struct KeylessHttp(Http);
+
+#[async_trait]
+impl JsonRpcClient for KeylessHttp {
+    type Error = HttpClientError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        self.0.request(method, params).await.map_err(|e| match e {
+            HttpClientError::ReqwestError(e) => HttpClientError::ReqwestError(e.without_url()),
+            e => e,
+        })
+    }
+}
+
+pub fn host_of(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// A JSON-RPC client over several http endpoints, failing over on transport errors.
+/// Throttled requests are retried on their endpoint, its rate is not a failure.
+#[derive(Debug)]
+pub struct FailoverClient {
+    endpoints: Vec<Endpoint>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FailoverError {
+    #[error("no endpoint configured")]
+    NoEndpoint,
+    #[error("rpc endpoint {host} is on chain {chain_id}, expected {expected}")]
+    ChainMismatch {
+        host: String,
+        chain_id: U256,
+        expected: u64,
+    },
+    #[error(transparent)]
+    Http(#[from] HttpClientError),
+}
+
+impl From<FailoverError> for ProviderError {
+    fn from(e: FailoverError) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(e))
+    }
+}
+
+impl FailoverClient {
+    pub fn new<S: AsRef<str>>(urls: &[S]) -> Result<Self, <Http as FromStr>::Err> {
+        let endpoints = urls
+            .iter()
+            .map(|url| {
+                let url = url.as_ref();
+                let host = host_of(url);
+                Ok(Endpoint {
+                    client: RateLimited::new(
+                        KeylessHttp(Http::from_str(url)?),
+                        rate_limit::budget_for(&host),
+                    ),
+                    host,
+                    score: AtomicI64::new(MAX_SCORE),
+                })
+            })
+            .collect::<Result<Vec<_>, <Http as FromStr>::Err>>()?;
+        Ok(Self { endpoints })
+    }
+
+    pub fn len(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.is_empty()
+    }
+
+    fn ranked(&self) -> Vec<&Endpoint> {
+        let mut endpoints: Vec<&Endpoint> = self.endpoints.iter().collect();
+        // stable, so equally scored endpoints keep the configured order
+        endpoints.sort_by_key(|endpoint| Reverse(endpoint.score.load(Ordering::Relaxed)));
+        endpoints
+    }
+
+    /// Check that every endpoint is on chain `expected`, so failing over never switches chains.
+    /// Endpoints which don't answer are dropped, their chain can't be checked.
+    pub async fn verify_chain_id(&mut self, expected: u64) -> Result<(), FailoverError> {
+        let answers = self.request_all::<_, U256>("eth_chainId", ()).await;
+        let mut verified = Vec::new();
+        let mut last_error = FailoverError::NoEndpoint;
+        for (endpoint, answer) in self.endpoints.drain(..).zip(answers) {
+            match answer {
+                Ok(chain_id) if chain_id == expected.into() => verified.push(endpoint),
+                Ok(chain_id) => {
+                    return Err(FailoverError::ChainMismatch {
+                        host: endpoint.host,
+                        chain_id,
+                        expected,
+                    })
+                }
+                Err(e) => {
+                    error!(
+                        "dropping rpc endpoint {}, its chain id can't be checked: {}",
+                        endpoint.host, e
+                    );
+                    last_error = e;
+                }
+            }
+        }
+        if verified.is_empty() {
+            return Err(last_error);
+        }
+        self.endpoints = verified;
+        Ok(())
+    }
+
+    /// Send the request to every endpoint, for cross-checking their answers.
+    pub async fn request_all<T, R>(&self, method: &str, params: T) -> Vec<Result<R, FailoverError>>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let params = &params;
+        let requests = self.endpoints.iter().map(|endpoint| async move {
+            metrics::rpc_call(method);
+            endpoint
+                .client
+                .request(method, params)
+                .await
+                .map_err(FailoverError::from)
+        });
+        futures::future::join_all(requests).await
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for FailoverClient {
+    type Error = FailoverError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let mut last_error = FailoverError::NoEndpoint;
+        for endpoint in self.ranked() {
+            match endpoint.client.request(method, &params).await {
+                Ok(result) => {
+                    endpoint.adjust_score(1);
+                    return Ok(result);
+                }
+                // the node did answer, another one would not answer differently
+                Err(e @ HttpClientError::JsonRpcError(_)) => return Err(e.into()),
+                // still throttled after backing off, the endpoint is up but the budget is spent
+                Err(e) if e.is_throttled() => return Err(e.into()),
+                Err(e) => {
+                    warn!(
+                        "rpc endpoint {} failed on {}, failing over: {}",
+                        endpoint.host, method, e
+                    );
+                    endpoint.adjust_score(-FAILURE_PENALTY);
+                    last_error = e.into();
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use ethers::prelude::*;
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::config::{self, RateLimit};
+use crate::metrics;
+
+/// Error messages of endpoints refusing a request because of its rate.
+const THROTTLED_PATTERNS: [&str; 4] = [
+    "too many requests",
+    "limit exceeded",
+    "rate limit",
+    "rate exceeded",
+];
+
+/// JSON-RPC error codes of throttled requests: infura's "limit exceeded", and the http
+/// status some endpoints send back as the code.
+const THROTTLED_CODES: [i64; 2] = [-32005, 429];
+
+/// Backoff after the first throttled request, doubled on every retry.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The budgets by endpoint host, the endpoints of one host usually belong to one account.
+static BUDGETS: Lazy<Mutex<HashMap<String, Arc<RequestBudget>>>> = Lazy::new(Default::default);
+
+/// The budget shared by the endpoints of `host`.
+pub fn budget_for(host: &str) -> Arc<RequestBudget> {
+    BUDGETS
+        .lock()
+        .unwrap()
+        .entry(host.to_string())
+        .or_insert_with(|| Arc::new(RequestBudget::new()))
+        .clone()
+}
+
+fn is_throttled_message(message: &str) -> bool {
+    let message = message.to_lowercase();
+    THROTTLED_PATTERNS
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
+
+fn is_throttled_rpc(error: &JsonRpcError) -> bool {
+    THROTTLED_CODES.contains(&error.code) || is_throttled_message(&error.message)
+}
+
+/// Client errors telling whether the endpoint refused a request because of its rate.
+pub trait Throttled {
+    fn is_throttled(&self) -> bool;
+}
+
+impl Throttled for HttpClientError {
+    fn is_throttled(&self) -> bool {
+        match self {
+            HttpClientError::ReqwestError(e) => {
+                e.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS)
+            }
+            HttpClientError::JsonRpcError(e) => is_throttled_rpc(e),
+            // the body of a non JSON-RPC answer, e.g. "Too Many Requests" with a 429
+            HttpClientError::SerdeJson { text, .. } => is_throttled_message(text),
+        }
+    }
+}
+
+impl Throttled for WsClientError {
+    fn is_throttled(&self) -> bool {
+        match self {
+            WsClientError::JsonRpcError(e) => is_throttled_rpc(e),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+    /// set while backing off after a throttled request, every request waits for it
+    paused_until: Option<Instant>,
+}
+
+/// A token bucket sized by the reloadable `web3.rate_limit` settings.
+#[derive(Debug)]
+pub struct RequestBudget {
+    state: Mutex<BucketState>,
+}
+
+impl RequestBudget {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                // clamped to the burst on the first refill
+                tokens: f64::INFINITY,
+                refilled_at: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    /// Wait until `permits` requests may be sent.
+    pub async fn acquire(&self, permits: u32) {
+        loop {
+            let live = config::current();
+            match self.try_acquire(live.web3().rate_limit(), permits as f64) {
+                Ok(()) => return,
+                Err(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+
+    /// Take `permits` tokens, or tell how long to wait before trying again.
+    fn try_acquire(&self, limit: &RateLimit, permits: f64) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if let Some(until) = state.paused_until {
+            if until > now {
+                return Err(until - now);
+            }
+            state.paused_until = None;
+        }
+        let rps = match limit.requests_per_second() {
+            Some(rps) => rps,
+            None => return Ok(()),
+        };
+        let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rps).min(limit.burst());
+        state.refilled_at = now;
+        // a batch larger than the burst may go once the bucket is full
+        let permits = permits.min(limit.burst());
+        if state.tokens >= permits {
+            state.tokens -= permits;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((permits - state.tokens) / rps))
+        }
+    }
+
+    /// Hold back every request for `backoff`.
+    fn pause(&self, backoff: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let until = Instant::now() + backoff;
+        state.paused_until = Some(state.paused_until.map_or(until, |u| u.max(until)));
+        state.tokens = 0.0;
+    }
+}
+
+impl Default for RequestBudget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A JSON-RPC client pacing its requests with a `RequestBudget`, and retrying
+/// those throttled by the endpoint with an exponential backoff.
+#[derive(Debug)]
+pub struct RateLimited<C> {
+    inner: C,
+    budget: Arc<RequestBudget>,
+}
+
+impl<C> RateLimited<C> {
+    /// Clients sharing `budget` share its rate, e.g. those of one provider account.
+    pub fn new(inner: C, budget: Arc<RequestBudget>) -> Self {
+        Self { inner, budget }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<C> JsonRpcClient for RateLimited<C>
+where
+    C: JsonRpcClient,
+    C::Error: Throttled,
+{
+    type Error = C::Error;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut retries = 0;
+        loop {
+            self.budget.acquire(1).await;
+            let error = match self.inner.request(method, &params).await {
+                Ok(result) => return Ok(result),
+                Err(e) => e,
+            };
+            let limit = config::current().web3().rate_limit().clone();
+            if !error.is_throttled() || retries >= limit.max_retries() {
+                return Err(error);
+            }
+            metrics::RPC_THROTTLED.with_label_values(&[method]).inc();
+            backoff = backoff.min(limit.max_backoff());
+            warn!(
+                "{} throttled by the endpoint, backing off for {:?}: {}",
+                method, backoff, error
+            );
+            self.budget.pause(backoff);
+            retries += 1;
+            backoff *= 2;
+        }
+    }
+}
+
+impl<C> PubsubClient for RateLimited<C>
+where
+    C: PubsubClient,
+    C::Error: Throttled,
+{
+    type NotificationStream = C::NotificationStream;
+
+    fn subscribe<T: Into<U256>>(&self, id: T) -> Result<Self::NotificationStream, Self::Error> {
+        self.inner.subscribe(id)
+    }
+
+    fn unsubscribe<T: Into<U256>>(&self, id: T) -> Result<(), Self::Error> {
+        self.inner.unsubscribe(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc_error(code: i64, message: &str) -> HttpClientError {
+        HttpClientError::JsonRpcError(JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        })
+    }
+
+    #[test]
+    fn test_is_throttled() {
+        assert!(rpc_error(-32005, "project ID request rate exceeded").is_throttled());
+        assert!(rpc_error(429, "").is_throttled());
+        assert!(rpc_error(-32000, "daily request limit exceeded").is_throttled());
+        let err = serde_json::from_str::<serde_json::Value>("Too Many Requests").unwrap_err();
+        assert!(HttpClientError::SerdeJson {
+            err,
+            text: "Too Many Requests".to_string()
+        }
+        .is_throttled());
+        // a 429 in a message is no status
+        assert!(!rpc_error(-32000, "header not found for block 429").is_throttled());
+        assert!(!rpc_error(3, "execution reverted: 429").is_throttled());
+    }
+
+    #[test]
+    fn test_budget() {
+        let limit = RateLimit::default();
+        let budget = RequestBudget::new();
+        assert!(budget.try_acquire(&limit, limit.burst()).is_ok());
+        assert!(budget.try_acquire(&limit, 1.0).is_err());
+        budget.pause(Duration::from_secs(60));
+        let wait = budget.try_acquire(&limit, 1.0).unwrap_err();
+        assert!(wait > Duration::from_secs(59));
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use ethers::prelude::Middleware;
+
+use crate::infos::{ContractInfoError, ContractInfos};
+use crate::restapi::{Asset, NewAssetReq, RestClient, RestError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReconcileError {
+    #[error("failed to enumerate on-chain tokens: {0}")]
+    Contract(#[from] ContractInfoError),
+    #[error("exchange rest api error: {0}")]
+    Rest(#[from] RestError),
+}
+
+/// Outcome of comparing the on-chain tokens with the exchange's assets.
+#[derive(Debug, Default)]
+pub struct ReconcileReport {
+    /// on-chain tokens compared with the exchange
+    pub checked: usize,
+    /// assets missing on the exchange, registered unless in dry-run
+    pub missing: Vec<Asset>,
+    /// differences between the on-chain and the exchange's view of an asset
+    pub mismatches: Vec<String>,
+    /// tokens whose metadata couldn't be resolved
+    pub unresolved: Vec<u16>,
+}
+
+fn compare(onchain: &Asset, exchange: &Asset) -> Vec<String> {
+    let fields = [
+        ("symbol", onchain.symbol.clone(), exchange.symbol.clone()),
+        (
+            "prec_save",
+            onchain.prec_save.to_string(),
+            exchange.prec_save.to_string(),
+        ),
+        (
+            "prec_show",
+            onchain.prec_show.to_string(),
+            exchange.prec_show.to_string(),
+        ),
+        (
+            "token_address",
+            onchain.token_address.to_lowercase(),
+            exchange.token_address.to_lowercase(),
+        ),
+    ];
+    fields
+        .iter()
+        .filter(|(_, expected, actual)| expected != actual)
+        .map(|(field, expected, actual)| {
+            format!(
+                "token #{} {}: on-chain {:?}, exchange {:?}",
+                onchain.rollup_token_id, field, expected, actual
+            )
+        })
+        .collect()
+}
+
+/// Compare every listed on-chain token with the exchange's assets and register the missing ones,
+/// unless `dry_run`.
+pub async fn reconcile_assets<M: Middleware>(
+    infos: &mut ContractInfos<M>,
+    rest_client: &RestClient,
+    dry_run: bool,
+) -> Result<ReconcileReport, ReconcileError> {
+    let exchange: HashMap<i32, Asset> = rest_client
+        .list_assets()
+        .await?
+        .into_iter()
+        .map(|asset| (asset.rollup_token_id, asset))
+        .collect();
+
+    let mut report = ReconcileReport::default();
+    for token_id in infos.fetch_token_ids().await? {
+        // unlisted tokens are skipped before their contract is queried
+        let asset = match infos.fetch_token_address(token_id).await {
+            Ok(address) if !infos.is_listed(address) => continue,
+            Ok(_) => infos.fetch_assets(token_id).await,
+            Err(e) => Err(e),
+        };
+        let asset = match asset {
+            Ok(asset) => asset,
+            Err(e) => {
+                warn!("cannot reconcile token #{}: {}", token_id, e);
+                report.unresolved.push(token_id);
+                continue;
+            }
+        };
+        report.checked += 1;
+        match exchange.get(&asset.rollup_token_id) {
+            Some(known) => report.mismatches.extend(compare(&asset, known)),
+            None => report.missing.push(asset),
+        }
+    }
+
+    for mismatch in &report.mismatches {
+        warn!("asset mismatch, {}", mismatch);
+    }
+    if !report.missing.is_empty() {
+        let ids: Vec<&str> = report.missing.iter().map(|a| a.id.as_str()).collect();
+        if dry_run {
+            warn!("assets missing on the exchange: {:?}", ids);
+        } else {
+            info!("registering assets missing on the exchange: {:?}", ids);
+            rest_client
+                .add_assets(&NewAssetReq {
+                    assets: report.missing.clone(),
+                    not_reload: false,
+                })
+                .await?;
+        }
+    }
+    info!(
+        "reconciled {} tokens: {} missing, {} mismatches, {} unresolved",
+        report.checked,
+        report.missing.len(),
+        report.mismatches.len(),
+        report.unresolved.len()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let onchain = Asset {
+            id: "USDT".to_string(),
+            symbol: "USDT".to_string(),
+            token_address: "0x46490225a85ddfd9d79256f8c5393c0428121488".to_string(),
+            rollup_token_id: 1,
+            prec_save: 6,
+            prec_show: 6,
+            ..Default::default()
+        };
+        let exchange = Asset {
+            token_address: onchain.token_address.to_uppercase(),
+            prec_show: 2,
+            ..onchain.clone()
+        };
+        let mismatches = compare(&onchain, &exchange);
+        assert_eq!(1, mismatches.len());
+        assert!(mismatches[0].contains("prec_show"));
+    }
+}
@@ -1,5 +1,17 @@
-use reqwest::StatusCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::header::{HeaderValue, CONTENT_TYPE};
+use reqwest::{Method, Request, Response, StatusCode};
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::secret::Secret;
+
+/// Backoff after the first failed attempt, doubled on every retry.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewAssetReq {
@@ -22,37 +34,319 @@ pub struct Asset {
     pub logo_uri: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewMarketReq {
+    pub market: Market,
+    /// registers the base asset together with the market
+    pub asset_base: Option<Asset>,
+    /// registers the quote asset together with the market
+    pub asset_quote: Option<Asset>,
+    #[serde(default)]
+    pub not_reload: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Market {
+    pub name: String,
+    pub base: String,
+    pub quote: String,
+    pub amount_precision: u32,
+    pub price_precision: u32,
+    pub fee_precision: u32,
+    pub min_amount: Decimal,
+}
+
+#[derive(Clone, Debug)]
+pub enum RestAuth {
+    None,
+    Bearer(Secret<String>),
+    Hmac {
+        key_id: String,
+        secret: Secret<String>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct RestOptions {
+    pub auth: RestAuth,
+    /// timeout of one attempt
+    pub timeout: Duration,
+    /// retries of requests failing with a 5xx or a transport error
+    pub max_retries: u32,
+}
+
+impl Default for RestOptions {
+    fn default() -> Self {
+        Self {
+            auth: RestAuth::None,
+            timeout: Duration::from_secs(10),
+            max_retries: 3,
+        }
+    }
+}
+
 pub struct RestClient {
     client: reqwest::Client,
     base_url: String,
+    options: RestOptions,
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum RestError {
     #[error("transport error: {0}")]
     Transport(#[from] reqwest::Error),
-    #[error("request failed with error: {0}")]
-    Http(StatusCode),
+    #[error("request failed with {status}: {body}")]
+    Http { status: StatusCode, body: String },
+    #[error("failed to encode the request: {0}")]
+    Encode(#[from] serde_json::Error),
+}
+
+impl RestError {
+    /// Keep the body of a failed response, it tells why the exchange rejected the request.
+    async fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .unwrap_or_else(|e| format!("<unreadable body: {}>", e));
+        RestError::Http {
+            status,
+            body: body.trim().to_string(),
+        }
+    }
+
+    /// Whether the request can be sent again after this error. Non-idempotent requests carry
+    /// an `Idempotency-Key`, so the exchange applies them once however often they are sent.
+    fn is_retryable(&self) -> bool {
+        match self {
+            RestError::Transport(e) => e.is_connect() || e.is_timeout(),
+            RestError::Http { status, .. } => status.is_server_error(),
+            RestError::Encode(_) => false,
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of `<timestamp><METHOD><path><body>`.
+fn signature(secret: &str, timestamp: u128, method: &Method, path: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(method.as_str().as_bytes());
+    mac.update(path.as_bytes());
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Hex key identifying one request across its retries, unique between requests.
+fn idempotency_key(method: &Method, path: &str, body: &[u8]) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut hasher = Sha256::new();
+    hasher.update(nanos.to_string().as_bytes());
+    hasher.update(method.as_str().as_bytes());
+    hasher.update(path.as_bytes());
+    hasher.update(body);
+    hex::encode(hasher.finalize())
 }
 
 impl RestClient {
-    pub fn new<P: AsRef<str>>(endpoint: P) -> Self {
-        Self {
-            client: reqwest::Client::default(),
+    pub fn new<P: AsRef<str>>(endpoint: P, options: RestOptions) -> Result<Self, RestError> {
+        let client = reqwest::Client::builder()
+            .timeout(options.timeout)
+            .connect_timeout(options.timeout)
+            .build()?;
+        Ok(Self {
+            client,
             base_url: endpoint.as_ref().to_owned(),
+            options,
+        })
+    }
+
+    fn authorize(&self, request: &mut Request) {
+        let headers = match &self.options.auth {
+            RestAuth::None => vec![],
+            RestAuth::Bearer(token) => {
+                vec![("authorization", format!("Bearer {}", token.expose()))]
+            }
+            RestAuth::Hmac { key_id, secret } => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                let body = request
+                    .body()
+                    .and_then(|body| body.as_bytes())
+                    .unwrap_or_default();
+                let signature = signature(
+                    secret.expose(),
+                    timestamp,
+                    request.method(),
+                    request.url().path(),
+                    body,
+                );
+                vec![
+                    ("x-api-key", key_id.clone()),
+                    ("x-timestamp", timestamp.to_string()),
+                    ("x-signature", signature),
+                ]
+            }
+        };
+        for (name, value) in headers {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                request.headers_mut().insert(name, value);
+            }
+        }
+    }
+
+    /// Send a request, retrying with a backoff on 5xx and transport errors. Non-idempotent
+    /// requests get an `Idempotency-Key`, the same for every attempt.
+    /// A response is returned whatever its status, except 5xx.
+    async fn send<T: Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&T>,
+    ) -> Result<Response, RestError> {
+        let url = format!("{}{}", self.base_url, path);
+        let body = body.map(serde_json::to_vec).transpose()?;
+        let key = if method.is_idempotent() {
+            None
+        } else {
+            Some(idempotency_key(
+                &method,
+                path,
+                body.as_deref().unwrap_or_default(),
+            ))
+        };
+        let mut backoff = INITIAL_BACKOFF;
+        let mut retries = 0;
+        loop {
+            let mut builder = self.client.request(method.clone(), url.as_str());
+            if let Some(body) = &body {
+                builder = builder
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.clone());
+            }
+            if let Some(key) = &key {
+                builder = builder.header("idempotency-key", key.as_str());
+            }
+            let mut request = builder.build()?;
+            self.authorize(&mut request);
+
+            let error = match self.client.execute(request).await {
+                Ok(response) if !response.status().is_server_error() => return Ok(response),
+                Ok(response) => RestError::from_response(response).await,
+                Err(e) => RestError::Transport(e),
+            };
+            if !error.is_retryable() || retries >= self.options.max_retries {
+                return Err(error);
+            }
+            warn!(
+                "rest-client: {} {} failed, retrying in {:?}: {}",
+                method, path, backoff, error
+            );
+            tokio::time::sleep(backoff).await;
+            retries += 1;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    async fn check(response: Response) -> Result<Response, RestError> {
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            Err(RestError::from_response(response).await)
         }
     }
 
     pub async fn add_assets(&self, req: &NewAssetReq) -> Result<(), RestError> {
-        let url: String = format!("{}/manage/market/assets", self.base_url);
         debug!("rest-client: {:?}", req);
-        let response = self.client.post(url.as_str()).json(req).send().await?;
+        let response = self
+            .send(Method::POST, "/manage/market/assets", Some(req))
+            .await?;
+        Self::check(response).await?;
+        Ok(())
+    }
 
-        let status = response.status();
-        if status.is_success() {
-            Ok(())
-        } else {
-            Err(RestError::Http(status))
+    /// All assets known to the exchange.
+    pub async fn list_assets(&self) -> Result<Vec<Asset>, RestError> {
+        let response = self
+            .send::<()>(Method::GET, "/manage/market/assets", None)
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    /// The asset `id`, `None` if the exchange doesn't know it.
+    pub async fn get_asset(&self, id: &str) -> Result<Option<Asset>, RestError> {
+        let path = format!("/manage/market/assets/{}", id);
+        let response = self.send::<()>(Method::GET, &path, None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
         }
+        Ok(Some(Self::check(response).await?.json().await?))
+    }
+
+    pub async fn add_market(&self, req: &NewMarketReq) -> Result<(), RestError> {
+        debug!("rest-client: {:?}", req);
+        let response = self
+            .send(Method::POST, "/manage/market/tradepairs", Some(req))
+            .await?;
+        Self::check(response).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Server};
+
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        let body = br#"{"assets":[]}"#;
+        assert_eq!(
+            "c6b78e2a159032ac747557ae1e6c964beb12c128f4d34d354648d6fd687e128d",
+            signature(
+                "s3cr3t",
+                1_630_000_000_000,
+                &Method::POST,
+                "/manage/market/assets",
+                body
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_asset() {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: hyper::Request<Body>| async move {
+                let response = match req.uri().path() {
+                    "/manage/market/assets/USDT" => hyper::Response::new(Body::from(
+                        r#"{"id":"USDT","symbol":"USDT","prec_save":6}"#,
+                    )),
+                    _ => hyper::Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty())
+                        .unwrap(),
+                };
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let client = RestClient::new(endpoint, RestOptions::default()).unwrap();
+        let asset = client.get_asset("USDT").await.unwrap().unwrap();
+        assert_eq!("USDT", asset.symbol);
+        assert_eq!(6, asset.prec_save);
+        assert!(client.get_asset("MKR").await.unwrap().is_none());
     }
 }
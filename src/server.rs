@@ -0,0 +1,75 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use crate::health::HEALTH;
+use crate::pending::DEPOSITS;
+use crate::{config, metrics};
+
+/// Bind `addr` and return the server of `/metrics`, `/healthz`, `/readyz` and `/deposits`,
+/// which runs until the process exits. Fails if `addr` can't be bound, e.g. when it is in use.
+pub fn serve(addr: SocketAddr) -> hyper::Result<impl Future<Output = hyper::Result<()>>> {
+    metrics::init();
+    let make_svc = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    let server = Server::try_bind(&addr)?;
+    info!("http server listening on {}", addr);
+    Ok(server.serve(make_svc))
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics::gather()))
+            .unwrap(),
+        (&Method::GET, "/healthz") => {
+            if HEALTH.is_alive(config::current().http().liveness_window()) {
+                text(StatusCode::OK, "ok")
+            } else {
+                text(StatusCode::SERVICE_UNAVAILABLE, "no block observed")
+            }
+        }
+        (&Method::GET, "/readyz") => {
+            let readiness = HEALTH.readiness().await;
+            let status = if readiness.is_ready() {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            Response::builder()
+                .status(status)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_vec(&readiness).unwrap()))
+                .unwrap()
+        }
+        (&Method::GET, "/deposits") => {
+            // `?to=<pubkey>` narrows the deposits to one account
+            let to = req
+                .uri()
+                .query()
+                .and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("to=")));
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&DEPOSITS.snapshot(to)).unwrap(),
+                ))
+                .unwrap()
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    };
+    Ok(response)
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
@@ -0,0 +1,55 @@
+use std::io;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Interrupt,
+    Terminate,
+}
+
+impl Signal {
+    /// Exit status following the shell convention of `128 + signo`.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Signal::Interrupt => 130,
+            Signal::Terminate => 143,
+        }
+    }
+}
+
+/// Shutdown notifier fired on the first SIGINT or SIGTERM.
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<Option<Signal>>,
+}
+
+impl Shutdown {
+    pub fn install() -> io::Result<Self> {
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let (tx, rx) = watch::channel(None);
+        tokio::spawn(async move {
+            let signal = tokio::select! {
+                _ = interrupt.recv() => Signal::Interrupt,
+                _ = terminate.recv() => Signal::Terminate,
+            };
+            info!("received {:?}, shutting down", signal);
+            let _ = tx.send(Some(signal));
+        });
+        Ok(Self { rx })
+    }
+
+    pub async fn recv(&mut self) -> Signal {
+        loop {
+            if let Some(signal) = *self.rx.borrow() {
+                return signal;
+            }
+            if self.rx.changed().await.is_err() {
+                // the notifier is gone, so no signal will ever come
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+}
@@ -37,6 +37,22 @@ impl Events {
             {% endfor %}
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        use Events::*;
+        match self {
+            {% for event in events %}{{ event.name | upper_camel }}(_) => "{{ event.name }}",
+            {% endfor %}
+        }
+    }
+
+    pub fn origin(&self) -> &::ethers::types::Log {
+        use Events::*;
+        match self {
+            {% for event in events %}{{ event.name | upper_camel }}(event) => &event.origin,
+            {% endfor %}
+        }
+    }
 }
 
 impl ::std::convert::TryFrom<::ethers::types::Log> for Events {